@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+use rust_stemmers::{Algorithm, Stemmer};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Scripts written without spaces between words: Han, kana, Thai, Lao, Khmer
+// and Myanmar. Word boundaries in them need a dictionary, so they're indexed
+// as single characters and overlapping character bigrams instead.
+fn is_unspaced(c: char) -> bool {
+    matches!(c as u32,
+        0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xf900..=0xfaff | 0x20000..=0x2ffff
+        | 0x3040..=0x30ff | 0x31f0..=0x31ff | 0xff66..=0xff9f
+        | 0x0e00..=0x0eff | 0x1780..=0x17ff | 0x1000..=0x109f)
+}
+
+// Bumped when segmentation changes, so indexes built before are rebuilt
+const SEGMENTATION_VERSION: u32 = 2;
+
+// Each character of the run followed by the bigram it starts: a, ab, b, bc, c.
+// Any part of a run, down to a single character, comes out the same way in
+// the same order, so queries match anywhere in a run and quoted ones can
+// check adjacency. Graphemes rather than chars, so thai vowel and tone marks
+// stay with their consonant. Offsets are into the whole text, the run starts
+// at offset.
+fn ngrams(run: &str, offset: usize, words: &mut Vec<(usize, usize)>) {
+    let graphemes: Vec<(usize, usize)> = run
+        .grapheme_indices(true)
+        .map(|(i, grapheme)| (offset + i, offset + i + grapheme.len()))
+        .collect();
+    for (i, grapheme) in graphemes.iter().enumerate() {
+        words.push(*grapheme);
+        if let Some(next) = graphemes.get(i + 1) {
+            words.push((grapheme.0, next.1));
+        }
+    }
+}
+
+// Byte ranges of the words in the text: UAX-29 words, with runs of unspaced
+// scripts turned into characters and bigrams. Punctuation and whitespace
+// between words are dropped. A word's position is its index in this list.
+fn segment(text: &str) -> Vec<(usize, usize)> {
+    let mut words = Vec::new();
+    // byte range of the unspaced run being collected
+    let mut run: Option<(usize, usize)> = None;
+    for (offset, word) in text.unicode_word_indices() {
+        let end = offset + word.len();
+        if word.chars().all(is_unspaced) {
+            run = match run {
+                // segmentation splits han into single characters, so join them back up
+                Some((start, run_end)) if run_end == offset => Some((start, end)),
+                Some((start, run_end)) => {
+                    ngrams(&text[start..run_end], start, &mut words);
+                    Some((offset, end))
+                }
+                None => Some((offset, end)),
+            };
+            continue;
+        }
+        if let Some((start, run_end)) = run.take() {
+            ngrams(&text[start..run_end], start, &mut words);
+        }
+        words.push((offset, end));
+    }
+    if let Some((start, run_end)) = run {
+        ngrams(&text[start..run_end], start, &mut words);
+    }
+    words
+}
+
+// Counts chars up to byte offsets that never go backwards
+struct CharCounter<'a> {
+    text: &'a str,
+    byte: usize,
+    chars: usize,
+}
+
+impl CharCounter<'_> {
+    fn to(&mut self, byte: usize) -> usize {
+        self.chars += self.text[self.byte..byte].chars().count();
+        self.byte = byte;
+        self.chars
+    }
+}
+
+// Char offsets of every word in the text, by position. These line up with the
+// positions analyze gives, whatever filters the analyzer has.
+pub(crate) fn spans(text: &str) -> Vec<(usize, usize)> {
+    // starts and ends each only move forward, even with overlapping bigrams
+    let mut starts = CharCounter { text, byte: 0, chars: 0 };
+    let mut ends = CharCounter { text, byte: 0, chars: 0 };
+    segment(text)
+        .into_iter()
+        .map(|(start, end)| (starts.to(start), ends.to(end)))
+        .collect()
+}
+
+// Snowball stemmers, by ISO 639-1 code
+const STEMMERS: &[(&str, Algorithm)] = &[
+    ("ar", Algorithm::Arabic), ("da", Algorithm::Danish), ("nl", Algorithm::Dutch),
+    ("en", Algorithm::English), ("fi", Algorithm::Finnish), ("fr", Algorithm::French),
+    ("de", Algorithm::German), ("el", Algorithm::Greek), ("hu", Algorithm::Hungarian),
+    ("it", Algorithm::Italian), ("no", Algorithm::Norwegian), ("pt", Algorithm::Portuguese),
+    ("ro", Algorithm::Romanian), ("ru", Algorithm::Russian), ("es", Algorithm::Spanish),
+    ("sv", Algorithm::Swedish), ("ta", Algorithm::Tamil), ("tr", Algorithm::Turkish),
+];
+
+// Languages the nltk stopword lists cover
+const STOPWORD_LANGUAGES: &[&str] = &[
+    "ar", "az", "da", "nl", "en", "fi", "fr", "de", "el", "hu", "id", "it",
+    "kk", "ne", "no", "pt", "ro", "ru", "sl", "es", "sv", "tg", "tr",
+];
+
+fn stopwords(language: &str) -> Option<&'static HashSet<&'static str>> {
+    static LISTS: OnceLock<HashMap<&'static str, HashSet<&'static str>>> = OnceLock::new();
+    LISTS
+        .get_or_init(|| {
+            STOPWORD_LANGUAGES
+                .iter()
+                .map(|code| (*code, stop_words::get(code).iter().copied().collect()))
+                .collect()
+        })
+        .get(language)
+}
+
+// The language specific steps of the chain, looked up once per text. Languages
+// without a stemmer or stopword list just skip those steps.
+struct Language {
+    stemmer: Option<Stemmer>,
+    stopwords: Option<&'static HashSet<&'static str>>,
+}
+
+impl Language {
+    fn new(code: &str) -> Self {
+        Language {
+            stemmer: STEMMERS
+                .iter()
+                .find(|(stemmer_code, _)| *stemmer_code == code)
+                .map(|(_, algorithm)| Stemmer::create(*algorithm)),
+            stopwords: stopwords(code),
+        }
+    }
+}
+
+// One step of the analyzer chain, applied to each word
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Filter {
+    // compatibility forms to their plain equivalents: ﬁ to fi, full width to ascii
+    Nfkc,
+    CaseFold,
+    // leading and trailing punctuation: "ethereum," and "(ethereum)" to ethereum
+    TrimPunctuation,
+    // é to e, so queries typed without accents still match
+    FoldDiacritics,
+    // drops words like "the" that match nearly every document; expects lowercase
+    Stopwords,
+    // staking to stake
+    Stem,
+}
+
+impl Filter {
+    pub(crate) fn from_name(name: &str) -> Option<Filter> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "nfkc" => Some(Filter::Nfkc),
+            "casefold" | "lowercase" => Some(Filter::CaseFold),
+            "punctuation" => Some(Filter::TrimPunctuation),
+            "diacritics" => Some(Filter::FoldDiacritics),
+            "stopwords" => Some(Filter::Stopwords),
+            "stem" => Some(Filter::Stem),
+            _ => None,
+        }
+    }
+
+    fn apply(self, word: String, language: &Language) -> String {
+        match self {
+            Filter::Nfkc => word.nfkc().collect(),
+            Filter::CaseFold => word.to_lowercase(),
+            Filter::TrimPunctuation => word.trim_matches(|c: char| !c.is_alphanumeric()).to_string(),
+            Filter::FoldDiacritics => word.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect(),
+            Filter::Stopwords => match language.stopwords {
+                Some(stopwords) if stopwords.contains(word.as_str()) => String::new(),
+                _ => word,
+            },
+            Filter::Stem => match &language.stemmer {
+                Some(stemmer) => stemmer.stem(&word).into_owned(),
+                None => word,
+            },
+        }
+    }
+}
+
+// Turns text into index terms. The same analyzer has to be used when building
+// the index and when parsing queries, or terms won't line up.
+#[derive(Debug, Clone)]
+pub(crate) struct Analyzer {
+    filters: Vec<Filter>,
+    // ISO 639-1 code for stemming and stopwords when nothing more specific is known
+    pub(crate) language: String,
+}
+
+impl Analyzer {
+    pub(crate) fn new(filters: Vec<Filter>, language: String) -> Self {
+        Analyzer { filters, language }
+    }
+
+    // Identifies what the analyzer turns text into: the filters, the default
+    // language and how text is split into words. An index only matches
+    // queries from the analyzer it was built with.
+    pub(crate) fn fingerprint(&self) -> String {
+        let filters: Vec<String> = self.filters.iter().map(|filter| format!("{:?}", filter)).collect();
+        format!("{};{};{}", filters.join(","), self.language, SEGMENTATION_VERSION)
+    }
+
+    pub(crate) fn with_language(&self, language: &str) -> Self {
+        Analyzer::new(self.filters.clone(), language.to_string())
+    }
+
+    // The term for a single word, or None if nothing is left of it
+    fn term(&self, word: &str, language: &Language) -> Option<String> {
+        let term = self.filters.iter().fold(word.to_string(), |word, filter| filter.apply(word, language));
+        (!term.is_empty()).then_some(term)
+    }
+
+    // Terms with their word positions, stemmed and filtered for the given
+    // language. Positions count every word in the text, including ones that
+    // nothing was left of.
+    pub(crate) fn analyze<'a>(&'a self, text: &'a str, language: &str) -> impl Iterator<Item = (usize, String)> + 'a {
+        let language = Language::new(language);
+        segment(text)
+            .into_iter()
+            .enumerate()
+            .filter_map(move |(i, (start, end))| Some((i, self.term(&text[start..end], &language)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analyzer() -> Analyzer {
+        Analyzer::new(vec![Filter::Nfkc, Filter::CaseFold, Filter::TrimPunctuation], "zh".to_string())
+    }
+
+    fn terms(text: &str) -> Vec<(usize, String)> {
+        analyzer().analyze(text, "zh").collect()
+    }
+
+    #[test]
+    fn unspaced_runs_are_characters_and_bigrams() {
+        let found: Vec<String> = terms("链上数据").into_iter().map(|(_, term)| term).collect();
+        assert_eq!(found, vec!["链", "链上", "上", "上数", "数", "数据", "据"]);
+        // a single character matches anywhere in a run, including the end
+        assert_eq!(terms("链"), vec![(0, "链".to_string())]);
+        assert!(terms("数据链").contains(&(4, "链".to_string())));
+    }
+
+    #[test]
+    fn part_of_a_run_analyzes_like_the_run() {
+        let text = "ENS 的链上数据 index";
+        let indexed = terms(text);
+        for query in ["链上", "上数据", "数", "的链上数据"] {
+            let query = terms(query);
+            let first = query[0].0;
+            // the query's terms appear in the text at the same distances from each other
+            let start = indexed.iter().find(|(_, term)| *term == query[0].1).unwrap().0;
+            for (i, term) in &query {
+                assert!(indexed.contains(&(start + i - first, term.clone())), "{:?} in {:?}", term, indexed);
+            }
+        }
+    }
+
+    #[test]
+    fn positions_index_into_spans() {
+        let text = "The (Bank) of England — 链上数据, ENS! naïve";
+        let analyzer = Analyzer::new(
+            vec![Filter::Nfkc, Filter::CaseFold, Filter::TrimPunctuation, Filter::Stopwords, Filter::Stem, Filter::FoldDiacritics],
+            "en".to_string(),
+        );
+        let spans = spans(text);
+        let chars: Vec<char> = text.chars().collect();
+        let word = |i: usize| chars[spans[i].0..spans[i].1].iter().collect::<String>();
+        let found: Vec<(usize, String)> = analyzer.analyze(text, "en").collect();
+        for (i, term) in &found {
+            // the word at the position analyzes to the term on its own
+            assert!(analyzer.analyze(&word(*i), "en").any(|(_, alone)| alone == *term), "{} at {} is {:?}", term, i, word(*i));
+        }
+        // stopwords leave gaps, the bigram run is a character and bigram per character
+        let positions: Vec<usize> = found.iter().map(|(i, _)| *i).collect();
+        assert_eq!(positions, vec![1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(spans.len(), 13);
+        assert_eq!(word(1), "Bank");
+        assert_eq!(word(5), "链上");
+        assert_eq!(word(10), "据");
+        assert_eq!(word(12), "naïve");
+    }
+}
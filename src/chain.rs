@@ -1,17 +1,15 @@
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
-use std::str::{from_utf8, FromStr};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 use ethers::prelude::*;
+use ethers::abi::{self, ParamType, Token};
 use ethers::providers::{Provider, Http};
 use std::sync::Arc;
 use ethers::utils::hex;
 use ethers::types::{H256};
-use substring::Substring;
-use bs58;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use cid::Cid;
-use crate::disk;
 
 // use ethers::utils::hex::hex;
 // use sha3::{Digest, Keccak256};
@@ -30,39 +28,98 @@ use crate::disk;
 //     return hex::encode(result);
 // }
 
-fn print_type_of<T>(_: &T) {
-    println!("{}", std::any::type_name::<T>())
+// EIP-1577 contenthash namespace codecs, as unsigned varints
+const IPFS_NS: &[u8] = &[0xe3, 0x01];
+const SWARM_NS: &[u8] = &[0xe4, 0x01];
+const ARWEAVE_NS: &[u8] = &[0x90, 0xb2, 0xca, 0x05];
+
+const SWARM_PREFIX: &str = "bzz://";
+const ARWEAVE_PREFIX: &str = "ar://";
+
+// A decoded contenthash. Ipfs holds the CID string, Swarm the hex reference
+// and Arweave the base64url transaction id.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum ContentHash {
+    Ipfs(String),
+    Swarm(String),
+    Arweave(String),
 }
 
-fn decode_cidv0(hex_string: &str) -> String { //Result<Vec<u8>, bs58::decode::Error> {
-    let bytes = hex::decode(hex_string).expect("Invalid hex string");
-    // Assuming the bytes are directly the raw data of the CID (you might need to adjust this)
-    // Create a CID instance from the bytes
-    let cid = Cid::try_from(bytes).expect("Failed to parse CID");
+// How content hashes are written in names.csv, failures.json and the index.
+// Plain CIDs are IPFS, so files written before other content types were
+// supported still load.
+impl fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentHash::Ipfs(cid) => write!(f, "{}", cid),
+            ContentHash::Swarm(reference) => write!(f, "{}{}", SWARM_PREFIX, reference),
+            ContentHash::Arweave(tx) => write!(f, "{}{}", ARWEAVE_PREFIX, tx),
+        }
+    }
+}
+
+impl FromStr for ContentHash {
+    type Err = anyhow::Error;
 
-    // Encode the CID's multihash to Base58
-    let ipfs_hash = cid.to_string();
-    return ipfs_hash;
-    // return bs58::decode(hex_string).into_vec();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            anyhow::bail!("Empty content hash");
+        }
+        if let Some(reference) = s.strip_prefix(SWARM_PREFIX) {
+            Ok(ContentHash::Swarm(reference.to_string()))
+        } else if let Some(tx) = s.strip_prefix(ARWEAVE_PREFIX) {
+            Ok(ContentHash::Arweave(tx.to_string()))
+        } else {
+            Ok(ContentHash::Ipfs(s.to_string()))
+        }
+    }
 }
 
-pub async fn get_events() -> Result<HashSet<String>, Box<dyn std::error::Error>> {
-    let path = "cids.csv";
+// The same CID can be written as v0 (Qm...) or in any v1 base; this gives
+// the base32 v1 form so references can be matched against the contenthashes
+pub(crate) fn normalize_cid(cid: &str) -> Option<String> {
+    let cid = Cid::try_from(cid).ok()?;
+    let cid = if cid.version() == cid::Version::V0 { cid.into_v1().ok()? } else { cid };
+    Some(cid.to_string())
+}
 
-    if let Ok(lines) = disk::load_events(path) {
-        println!("Loaded events from disk successfully");
-        return Ok(lines);
+fn decode_contenthash(bytes: &[u8]) -> Result<ContentHash, anyhow::Error> {
+    if let Some(rest) = bytes.strip_prefix(IPFS_NS) {
+        let cid = Cid::try_from(rest)?;
+        Ok(ContentHash::Ipfs(cid.to_string()))
+    } else if let Some(rest) = bytes.strip_prefix(SWARM_NS) {
+        // Swarm references are wrapped in a CID with the swarm-manifest codec
+        // and a keccak-256 multihash; gateways want the bare digest in hex.
+        let cid = Cid::try_from(rest)?;
+        Ok(ContentHash::Swarm(hex::encode(cid.hash().digest())))
+    } else if let Some(rest) = bytes.strip_prefix(ARWEAVE_NS) {
+        // Arweave stores the raw transaction id bytes
+        Ok(ContentHash::Arweave(URL_SAFE_NO_PAD.encode(rest)))
     } else {
-        println!("Getting events from network");
+        Err(anyhow::anyhow!("Unsupported contenthash codec: {}", hex::encode(bytes)))
     }
+}
+
+// Contenthash of every name that has one, by namehash
+pub(crate) type Names = HashMap<H256, ContentHash>;
 
+// Applies the ContenthashChanged logs after last_block to names, and returns
+// the last block read. Reading them again is harmless, the last log for a name
+// wins either way.
+pub async fn get_events(names: &mut Names, last_block: Option<u64>) -> Result<Option<u64>, Box<dyn std::error::Error>> {
     // Connect to the Ethereum network
     let provider = Provider::<Http>::try_from("https://mainnet.infura.io/v3/eb4d26a77ba04ab2ba6bf10a6a86f120")?;
     let provider = Arc::new(provider);
     println!("Connected to ethereum provider infura");
 
     // Get the latest block number
-    let to_block = provider.get_block_number().await?;
+    let to_block = provider.get_block_number().await?.as_u64();
+    let from_block = last_block.map_or(0, |block| block + 1);
+    if from_block > to_block {
+        println!("No new blocks since {}", to_block);
+        return Ok(last_block);
+    }
 
     // Define the event signature for "SetContentHash()"
     // This hash is typically the Keccak-256 hash of the event signature string
@@ -76,8 +133,6 @@ pub async fn get_events() -> Result<HashSet<String>, Box<dyn std::error::Error>>
         .map_err(|_| "Failed to parse event signature hash")?;
 
     // let event_signature_hash = H256::from_slice(&hex::decode("e379c1624ed7e714cc0937528a32359d69d5281337765313dba4e081b72d7578")?);
-    // let from_block = to_block - 100000;
-    let from_block = 0;
 
     println!("Filtering for events with signature {} from block {} to {}", event_sig, from_block, to_block);
     // Create a filter for the event
@@ -94,29 +149,90 @@ pub async fn get_events() -> Result<HashSet<String>, Box<dyn std::error::Error>>
     let logs = provider.get_logs(&filter).await?;
 
     println!("Acquired {} logs", logs.len());
-    let mut ipfs_hashes = HashSet::new();
-
+    // Logs come in chain order, so the last one for a name is its current
+    // contenthash and the ones before it were changed since
     // Process the logs
     for log in logs {
         // TODO: how to get domain from log???
-        let d = log.data;
-        let h = hex::encode(d);
-        let v: Vec<_> = h.split("26e301").collect();
+        let Some(node) = log.topics.get(1).copied() else {
+            println!("Log without a node");
+            continue;
+        };
+        let h = hex::encode(&log.data);
         println!("Log data (original): {}", h);
-        let l = v.len();
-        match l {
-            2 => {
-                let bytestring = v[1].substring(0, 72);
-                let ipfs = decode_cidv0(bytestring);
-                println!("Ipfs: {} - {:?}", bytestring, ipfs);
-                ipfs_hashes.insert(ipfs);
+        // ContenthashChanged(bytes32 indexed node, bytes hash): data is the abi encoded hash
+        let bytes = match abi::decode(&[ParamType::Bytes], &log.data) {
+            Ok(tokens) => match tokens.into_iter().next() {
+                Some(Token::Bytes(bytes)) => bytes,
+                _ => {
+                    println!("bad vector");
+                    continue;
+                }
             },
-            1 => println!("Unknown: {}", v[0]),
-            _ => println!("bad vector")
+            Err(e) => {
+                println!("Could not decode log data: {}", e);
+                continue;
+            }
+        };
+        // An empty contenthash means the record was cleared
+        if bytes.is_empty() {
+            names.remove(&node);
+            continue;
         }
+        match decode_contenthash(&bytes) {
+            Ok(content_hash) => {
+                println!("Content hash: {} - {}", hex::encode(&bytes), content_hash);
+                names.insert(node, content_hash);
+            },
+            Err(e) => {
+                println!("Unknown: {}", e);
+                // whatever the name pointed at before isn't current anymore
+                names.remove(&node);
+            }
+        }
+    }
+
+    Ok(Some(to_block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Contenthash vectors from EIP-1577 and the ENS docs
+    #[test]
+    fn decodes_ipfs_swarm_and_arweave() {
+        let ipfs = hex::decode("e3010170122029f2d17be6139079dc48696d1f582a8530eb9805b561eda517e22a892c7e3f1f").unwrap();
+        let expected = normalize_cid("QmRAQB6YaCyidP37UdDnjFY5vQuiBrcqdyoW1CuDgwxkD4").unwrap();
+        assert_eq!(decode_contenthash(&ipfs).unwrap(), ContentHash::Ipfs(expected));
+
+        let swarm = hex::decode("e40101fa011b20d1de9994b4d039f6548d191eb26786769f580809256b4685ef316805265ea162").unwrap();
+        let reference = "d1de9994b4d039f6548d191eb26786769f580809256b4685ef316805265ea162";
+        assert_eq!(decode_contenthash(&swarm).unwrap(), ContentHash::Swarm(reference.to_string()));
+
+        let arweave = hex::decode("90b2ca05cacdf63edf2e0bb4eb5711dd38b0723aca5f3c4ab62ceeb7c1110740833d4894").unwrap();
+        let tx = "ys32Pt8uC7TrVxHdOLByOspfPEq2LO63wREHQIM9SJQ";
+        assert_eq!(decode_contenthash(&arweave).unwrap(), ContentHash::Arweave(tx.to_string()));
     }
 
-    disk::save_events(path, ipfs_hashes.clone());
+    #[test]
+    fn rejects_other_codecs() {
+        // onion
+        let onion = hex::decode("bc037a716b746c7a6e6b6e7566716767").unwrap();
+        assert!(decode_contenthash(&onion).is_err());
+        // an ipfs namespace without a valid cid after it
+        assert!(decode_contenthash(&[0xe3, 0x01, 0x01]).is_err());
+    }
 
-    Ok(ipfs_hashes)
-}
\ No newline at end of file
+    #[test]
+    fn content_hashes_round_trip_through_strings() {
+        for hash in [
+            ContentHash::Ipfs("QmRAQB6YaCyidP37UdDnjFY5vQuiBrcqdyoW1CuDgwxkD4".to_string()),
+            ContentHash::Swarm("d1de9994b4d039f6548d191eb26786769f580809256b4685ef316805265ea162".to_string()),
+            ContentHash::Arweave("ys32Pt8uC7TrVxHdOLByOspfPEq2LO63wREHQIM9SJQ".to_string()),
+        ] {
+            assert_eq!(hash.to_string().parse::<ContentHash>().unwrap(), hash);
+        }
+        assert!("".parse::<ContentHash>().is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use encoding_rs::{Encoding, UTF_8};
+use regex::bytes::Regex;
+use std::sync::OnceLock;
+
+// Browsers only look this far into a document for a <meta> charset
+const META_PRESCAN_LEN: usize = 1024;
+
+fn meta_charset_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    // Covers both <meta charset="x"> and
+    // <meta http-equiv="Content-Type" content="text/html; charset=x">
+    RE.get_or_init(|| Regex::new(r#"(?i)<meta[^>]+charset\s*=\s*["']?\s*([a-z0-9_:.\-]+)"#).unwrap())
+}
+
+fn header_charset(content_type: &str) -> Option<&'static Encoding> {
+    content_type
+        .split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, label)| Encoding::for_label(label.trim().trim_matches('"').as_bytes()))
+}
+
+fn meta_charset(body: &[u8]) -> Option<&'static Encoding> {
+    let head = &body[..body.len().min(META_PRESCAN_LEN)];
+    let label = meta_charset_regex().captures(head)?.get(1)?;
+    let encoding = Encoding::for_label(label.as_bytes())?;
+    // A utf-16 meta tag can't be true if we could read it as ascii
+    if encoding == encoding_rs::UTF_16LE || encoding == encoding_rs::UTF_16BE {
+        Some(UTF_8)
+    } else {
+        Some(encoding)
+    }
+}
+
+// Bodies over the size limit are cut off wherever the limit falls, so utf-8
+// that's only invalid because its last character is incomplete is still utf-8
+fn guess_charset(body: &[u8]) -> &'static Encoding {
+    match std::str::from_utf8(body) {
+        Ok(_) => return UTF_8,
+        Err(e) if e.error_len().is_none() => return UTF_8,
+        Err(_) => {}
+    }
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(body, true);
+    detector.guess(None, true)
+}
+
+// Work out the encoding the way a browser would: byte order mark, then the
+// Content-Type charset, then <meta>, and finally a guess from the bytes.
+// The <meta> prescan runs whatever the content type, since html served as
+// octet-stream is common and other documents won't have a meta tag anyway.
+pub(crate) fn detect(content_type: &str, body: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(body) {
+        return encoding;
+    }
+    if let Some(encoding) = header_charset(content_type) {
+        return encoding;
+    }
+    if let Some(encoding) = meta_charset(body) {
+        return encoding;
+    }
+    guess_charset(body)
+}
+
+// Transcode the body to utf-8. Malformed sequences become U+FFFD.
+pub(crate) fn decode(content_type: &str, body: &[u8]) -> String {
+    let encoding = detect(content_type, body);
+    let (text, _, had_errors) = encoding.decode(body);
+    if had_errors {
+        println!("Body is not valid {}, some characters were replaced", encoding.name());
+    }
+    text.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_utf8_is_still_utf8() {
+        let text = "Ethereum Name Service – dezentrale Namen für Wallets, Websites und mehr: äöü ß € 日本語";
+        let body = text.as_bytes();
+        // cut in the middle of the last character
+        let cut = &body[..body.len() - 1];
+        assert!(std::str::from_utf8(cut).is_err());
+        assert_eq!(detect("application/octet-stream", cut), UTF_8);
+        assert!(decode("application/octet-stream", cut).starts_with("Ethereum Name Service – dezentrale"));
+
+        // invalid before the end isn't
+        let mut latin1 = b"caf\xe9 cr\xe8me br\xfbl\xe9e, na\xefve r\xe9sum\xe9".to_vec();
+        latin1.extend_from_slice(&body[..body.len() - 1]);
+        assert_ne!(detect("", &latin1), UTF_8);
+    }
+
+    #[test]
+    fn header_and_meta_come_before_guessing() {
+        let body = b"<html><head><meta charset=\"shift_jis\"></head></html>";
+        assert_eq!(detect("text/html; charset=\"ISO-8859-2\"", body), encoding_rs::ISO_8859_2);
+        assert_eq!(detect("text/html", body), encoding_rs::SHIFT_JIS);
+        assert_eq!(detect("text/html", b"\xef\xbb\xbfplain"), UTF_8);
+    }
+}
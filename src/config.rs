@@ -0,0 +1,220 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use crate::analyze::{Analyzer, Filter};
+use crate::chain::ContentHash;
+
+const DEFAULT_IPFS_GATEWAY: &str = "https://ipfs.io/ipfs";
+const DEFAULT_SWARM_GATEWAY: &str = "https://api.gateway.ethswarm.org/bzz";
+const DEFAULT_ARWEAVE_GATEWAY: &str = "https://arweave.net";
+
+fn env_or(key: &str, default: &str) -> String {
+    env::var(key)
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+fn env_parse<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            println!("Ignoring invalid value for {}: {}", key, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+// Gateway base urls, without a trailing slash
+#[derive(Debug, Clone)]
+pub(crate) struct Gateways {
+    pub(crate) ipfs: String,
+    pub(crate) swarm: String,
+    pub(crate) arweave: String,
+}
+
+impl Gateways {
+    fn from_env() -> Self {
+        let gateway = |key, default| env_or(key, default).trim_end_matches('/').to_string();
+        Gateways {
+            ipfs: gateway("IPFS_GATEWAY", DEFAULT_IPFS_GATEWAY),
+            swarm: gateway("SWARM_GATEWAY", DEFAULT_SWARM_GATEWAY),
+            arweave: gateway("ARWEAVE_GATEWAY", DEFAULT_ARWEAVE_GATEWAY),
+        }
+    }
+
+    pub(crate) fn url(&self, hash: &ContentHash) -> String {
+        match hash {
+            ContentHash::Ipfs(cid) => format!("{}/{}", self.ipfs, cid),
+            // the trailing slash makes the gateway serve the manifest's index document
+            ContentHash::Swarm(reference) => format!("{}/{}/", self.swarm, reference),
+            ContentHash::Arweave(tx) => format!("{}/{}", self.arweave, tx),
+        }
+    }
+}
+
+// How make_request retries 429s, 502/503/504s, timeouts and connection errors
+#[derive(Debug, Clone)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: usize,
+    pub(crate) base_delay: Duration,
+    pub(crate) max_delay: Duration,
+    // applies to each attempt, not the whole retry loop
+    pub(crate) timeout: Duration,
+}
+
+impl RetryPolicy {
+    fn from_env() -> Self {
+        RetryPolicy {
+            max_retries: env_parse("FETCH_MAX_RETRIES", 5),
+            base_delay: Duration::from_millis(env_parse("FETCH_BASE_DELAY_MS", 1000)),
+            max_delay: Duration::from_secs(env_parse("FETCH_MAX_DELAY_SECS", 60)),
+            timeout: Duration::from_secs(env_parse("FETCH_TIMEOUT_SECS", 30)),
+        }
+    }
+}
+
+// How much of a response body we keep
+#[derive(Debug, Clone)]
+pub(crate) struct BodyLimits {
+    pub(crate) max_body_bytes: usize,
+    // for bodies sniffed as images, video, archives and the like
+    pub(crate) max_binary_bytes: usize,
+}
+
+impl BodyLimits {
+    fn from_env() -> Self {
+        BodyLimits {
+            max_body_bytes: env_parse("MAX_BODY_BYTES", 10 * 1024 * 1024),
+            max_binary_bytes: env_parse("MAX_BINARY_BYTES", 64 * 1024),
+        }
+    }
+}
+
+// How many requests may be in flight against each gateway, and how many
+// documents may be parsed at once on the blocking pool
+#[derive(Debug, Clone)]
+pub(crate) struct Concurrency {
+    pub(crate) ipfs: usize,
+    pub(crate) swarm: usize,
+    pub(crate) arweave: usize,
+    pub(crate) parse: usize,
+    // pages being rendered at once, across all gateways
+    pub(crate) render: usize,
+}
+
+impl Concurrency {
+    fn from_env() -> Self {
+        let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+        Concurrency {
+            ipfs: env_parse("IPFS_CONCURRENCY", 10_usize).max(1),
+            swarm: env_parse("SWARM_CONCURRENCY", 4_usize).max(1),
+            arweave: env_parse("ARWEAVE_CONCURRENCY", 4_usize).max(1),
+            parse: env_parse("PARSE_CONCURRENCY", cpus).max(1),
+            render: env_parse("RENDER_CONCURRENCY", 2_usize).max(1),
+        }
+    }
+}
+
+// When content hashes that failed to fetch are tried again. After
+// quarantine_after failed attempts a site is reported as unavailable.
+#[derive(Debug, Clone)]
+pub(crate) struct FailurePolicy {
+    pub(crate) base_recheck: Duration,
+    pub(crate) max_recheck: Duration,
+    pub(crate) quarantine_after: u32,
+}
+
+impl FailurePolicy {
+    fn from_env() -> Self {
+        FailurePolicy {
+            base_recheck: Duration::from_secs(env_parse("RECHECK_BASE_HOURS", 1) * 60 * 60),
+            max_recheck: Duration::from_secs(env_parse("RECHECK_MAX_DAYS", 30) * 24 * 60 * 60),
+            quarantine_after: env_parse("QUARANTINE_AFTER", 5),
+        }
+    }
+}
+
+// How pages that are just a javascript app shell get their content
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum RenderMode {
+    // index the static html as served
+    Static,
+    // pull text out of the page's script bundles and web app manifest
+    Bundle,
+    // hand the page to an external renderer, e.g. a headless browser
+    Command(String),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RenderConfig {
+    pub(crate) mode: RenderMode,
+    pub(crate) timeout: Duration,
+}
+
+impl RenderConfig {
+    fn from_env() -> Self {
+        let mode = match env_or("RENDERER", "static").to_ascii_lowercase().as_str() {
+            "static" | "none" => RenderMode::Static,
+            "command" => RenderMode::Command(env_or("RENDER_COMMAND", "chromium --headless --dump-dom")),
+            "bundle" => RenderMode::Bundle,
+            other => {
+                println!("Unknown renderer {}, indexing static html only", other);
+                RenderMode::Static
+            }
+        };
+        RenderConfig {
+            mode,
+            timeout: Duration::from_secs(env_parse("RENDER_TIMEOUT_SECS", 30)),
+        }
+    }
+}
+
+// ANALYZER is a comma separated list of filters, applied in order. Accents are
+// folded last since the stemmers and stopword lists expect them.
+fn analyzer_from_env() -> Analyzer {
+    let names = env_or("ANALYZER", "nfkc,casefold,punctuation,stopwords,stem,diacritics");
+    let filters = names
+        .split(',')
+        .filter(|name| !name.trim().is_empty())
+        .filter_map(|name| {
+            let filter = Filter::from_name(name);
+            if filter.is_none() {
+                println!("Unknown analyzer filter {}, skipping", name.trim());
+            }
+            filter
+        })
+        .collect();
+    Analyzer::new(filters, env_or("LANGUAGE", "en").to_ascii_lowercase())
+}
+
+// Runtime settings, read from the environment with defaults for everything
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub(crate) gateways: Gateways,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) body_limits: BodyLimits,
+    pub(crate) concurrency: Concurrency,
+    // where fetched bodies are kept between builds, keyed by content hash
+    pub(crate) cache_dir: PathBuf,
+    pub(crate) failures: FailurePolicy,
+    pub(crate) render: RenderConfig,
+    // how text is turned into terms, at index and query time
+    pub(crate) analyzer: Analyzer,
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Self {
+        Config {
+            gateways: Gateways::from_env(),
+            retry: RetryPolicy::from_env(),
+            body_limits: BodyLimits::from_env(),
+            concurrency: Concurrency::from_env(),
+            cache_dir: PathBuf::from(env_or("CONTENT_CACHE_DIR", "cache")),
+            failures: FailurePolicy::from_env(),
+            render: RenderConfig::from_env(),
+            analyzer: analyzer_from_env(),
+        }
+    }
+}
@@ -1,80 +1,263 @@
-use std::collections::HashSet;
-use std::fs::File;
-use std::path::Path;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use ethers::types::H256;
+use crate::chain::{ContentHash, Names};
 use crate::index;
+use crate::postings;
+use crate::segment::{self, Segment};
+use crate::store::{AltTexts, Deletions, Manifest, SCHEMA_VERSION};
+use crate::failures::{FailureRecord, Failures};
+use crate::fetch::Fetched;
 use std::io::{self, BufRead, Write, Error};
 
 
-pub fn save_docs(filename: &Path, docs: &index::Docs) -> io::Result<()> {
-    // Open a file in write mode
-    let file = File::create(filename)?;
-    let writer = io::BufWriter::new(file);
+// Writes to a temporary file next to the target, fsyncs it and renames it
+// over the target, so after a crash there's either the old file or the new
+// one, never half of one
+fn write_atomic<T>(filename: &Path, write: impl FnOnce(&mut io::BufWriter<&File>) -> io::Result<T>) -> io::Result<T> {
+    let mut tmp_name = filename.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = filename.with_file_name(tmp_name);
+    let file = File::create(&tmp_path)?;
+    let mut writer = io::BufWriter::new(&file);
+    let written = write(&mut writer).and_then(|value| writer.flush().map(|_| value));
+    drop(writer);
+    let value = match written.and_then(|value| file.sync_all().map(|_| value)) {
+        Ok(value) => value,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+    fs::rename(&tmp_path, filename)?;
+    sync_dir(filename)?;
+    Ok(value)
+}
 
-    // Serialize the HashMap into JSON and write it to the file
-    serde_json::to_writer(writer, &docs).map_err(|e| Error::new(io::ErrorKind::Other, e))
+// The rename itself is only durable once the directory is synced
+#[cfg(unix)]
+fn sync_dir(filename: &Path) -> io::Result<()> {
+    match filename.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
 }
 
-pub fn load_docs(filename: &Path) -> io::Result<index::Docs> {
-    println!("Loading docs from file: {:?}", filename.to_str());
-    // Open the file in read mode
-    let file = File::open(filename)?;
-    let reader = io::BufReader::new(file);
+#[cfg(not(unix))]
+fn sync_dir(_filename: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn corrupt(filename: &Path, problem: &str) -> Error {
+    Error::new(io::ErrorKind::InvalidData, format!("{} is corrupt: {}", filename.display(), problem))
+}
+
+// Errors from opening a file don't say which one
+fn in_file(filename: &Path, e: Error) -> Error {
+    Error::new(e.kind(), format!("{}: {}", filename.display(), e))
+}
+
+// A segment holds the index and stored documents of one build or merge.
+// Returns its checksum for the manifest.
+pub fn save_segment(filename: &Path, index: &postings::Index, docs: &index::Docs) -> io::Result<u32> {
+    write_atomic(filename, |writer| segment::write(writer, index, docs))
+}
+
+// Merges the given documents of the segments into a new one. Returns its
+// checksum for the manifest.
+pub fn save_merged(filename: &Path, inputs: &[(&Segment, Vec<postings::DocId>)]) -> io::Result<u32> {
+    write_atomic(filename, |writer| segment::write_merged(writer, inputs))
+}
+
+// Only the footer is checked against the manifest, the tables are checked by
+// Segment::verify
+pub fn load_segment(filename: &Path, checksum: u32) -> io::Result<Segment> {
+    println!("Loading segment from file: {:?}", filename.to_str());
+    let segment = Segment::open(filename).map_err(|e| in_file(filename, e))?;
+    let actual = segment.checksum();
+    if actual != checksum {
+        return Err(corrupt(filename, &format!("checksum is {:08x} but the manifest has {:08x}", actual, checksum)));
+    }
+    Ok(segment)
+}
+
+// Returns the checksum for the manifest
+pub fn save_deletions(filename: &Path, deletions: &Deletions) -> io::Result<u32> {
+    write_atomic(filename, |writer| writer.write_all(deletions.bytes()))?;
+    Ok(crc32fast::hash(deletions.bytes()))
+}
+
+pub fn load_deletions(filename: &Path, checksum: u32, docs: usize) -> io::Result<Deletions> {
+    let bytes = fs::read(filename).map_err(|e| in_file(filename, e))?;
+    if crc32fast::hash(&bytes) != checksum {
+        return Err(corrupt(filename, "checksum doesn't match the manifest"));
+    }
+    if bytes.len() != docs.div_ceil(8) {
+        return Err(corrupt(filename, &format!("{} bytes for {} documents", bytes.len(), docs)));
+    }
+    Ok(Deletions::from_bytes(bytes, docs))
+}
+
+// Returns the checksum for the manifest
+pub fn save_alt_texts(filename: &Path, alt_texts: &AltTexts) -> io::Result<u32> {
+    let bytes = serde_json::to_vec(alt_texts).map_err(Error::other)?;
+    write_atomic(filename, |writer| writer.write_all(&bytes))?;
+    Ok(crc32fast::hash(&bytes))
+}
+
+pub fn load_alt_texts(filename: &Path, checksum: u32) -> io::Result<AltTexts> {
+    let bytes = fs::read(filename).map_err(|e| in_file(filename, e))?;
+    if crc32fast::hash(&bytes) != checksum {
+        return Err(corrupt(filename, "checksum doesn't match the manifest"));
+    }
+    serde_json::from_slice(&bytes).map_err(|e| corrupt(filename, &e.to_string()))
+}
 
-    // Deserialize the JSON from the file back into a HashMap
-    serde_json::from_reader(reader).map_err(|e| Error::new(io::ErrorKind::Other, e))
+pub fn save_manifest(filename: &Path, manifest: &Manifest) -> io::Result<()> {
+    write_atomic(filename, |writer| serde_json::to_writer_pretty(writer, manifest).map_err(Error::other))
 }
 
-pub fn save_index(filename: &Path, index: &index::Index) -> io::Result<()> {
-    // Open a file in write mode
-    let file = File::create(filename)?;
-    let writer = io::BufWriter::new(file);
+// The version is checked before the rest is parsed, so a manifest from
+// another version is reported as that rather than as corrupt
+pub fn load_manifest(filename: &Path) -> io::Result<Option<Manifest>> {
+    if !filename.exists() {
+        return Ok(None);
+    }
+    let bytes = fs::read(filename).map_err(|e| in_file(filename, e))?;
+    let value: serde_json::Value = serde_json::from_slice(&bytes).map_err(|e| corrupt(filename, &e.to_string()))?;
+    let version = value.get("schema_version").and_then(serde_json::Value::as_u64);
+    if version != Some(SCHEMA_VERSION as u64) {
+        let found = version.map_or("no schema version".to_string(), |version| format!("schema version {}", version));
+        return Err(Error::new(io::ErrorKind::InvalidData, format!(
+            "{} has {} but this build reads {}, delete the index directory to rebuild it",
+            filename.display(), found, SCHEMA_VERSION)));
+    }
+    serde_json::from_value(value).map(Some).map_err(|e| corrupt(filename, &e.to_string()))
+}
 
-    // Serialize the HashMap into JSON and write it to the file
-    serde_json::to_writer(writer, &index).map_err(|e| Error::new(io::ErrorKind::Other, e))
+pub fn save_failures(filename: &Path, failures: &Failures) -> io::Result<()> {
+    write_atomic(filename, |writer| serde_json::to_writer(writer, &failures).map_err(Error::other))
 }
 
-pub fn load_index(filename: &Path) -> io::Result<index::Index> {
-    println!("Loading index from file: {:?}", filename.to_str());
-    // Open the file in read mode
+pub fn load_failures(filename: &Path) -> io::Result<Failures> {
+    if !filename.exists() {
+        return Ok(Failures::new());
+    }
     let file = File::open(filename)?;
     let reader = io::BufReader::new(file);
+    serde_json::from_reader(reader).map_err(Error::other)
+}
 
-    // Deserialize the JSON from the file back into a HashMap
-    serde_json::from_reader(reader).map_err(|e| Error::new(io::ErrorKind::Other, e))
+pub fn save_unavailable_report(filename: &Path, unavailable: &[(&String, &FailureRecord)]) -> io::Result<()> {
+    write_atomic(filename, |file| {
+        writeln!(file, "content_hash,attempts,first_failed,last_failed,reason")?;
+        for (key, record) in unavailable {
+            writeln!(file, "{},{},{},{},\"{}\"", key, record.attempts, record.first_failed,
+                     record.last_failed, record.reason.replace('"', "'"))?;
+        }
+        Ok(())
+    })
 }
 
 
-pub fn load_events(filename: &str) -> Result<HashSet<String>, Error> {
-    let path = Path::new(filename);
-    if path.exists() {
-        let file = File::open(path)?;
-        let reader = io::BufReader::new(file);
-        // Create a HashSet to store the lines
-        let mut lines = HashSet::new();
+// One name per line, its namehash and contenthash separated by a comma. None
+// if there's no file yet.
+pub fn load_names(filename: &Path) -> io::Result<Option<Names>> {
+    if !filename.exists() {
+        return Ok(None);
+    }
+    let reader = io::BufReader::new(File::open(filename)?);
+    let mut names = Names::new();
+    for line in reader.lines() {
+        let line = line?;
+        let parsed = line
+            .split_once(',')
+            .and_then(|(node, hash)| Some((node.parse::<H256>().ok()?, hash.parse::<ContentHash>().ok()?)));
+        let Some((node, hash)) = parsed else {
+            return Err(corrupt(filename, "Unreadable name"));
+        };
+        names.insert(node, hash);
+    }
+    Ok(Some(names))
+}
 
-        // Read lines using the lines iterator from BufReader
-        for line_result in reader.lines() {
-            let line = line_result?;  // Handle potential I/O errors
-            lines.insert(line);       // Insert each line into the HashSet
+pub fn save_names(filename: &Path, names: &Names) -> io::Result<()> {
+    write_atomic(filename, |file| {
+        for (node, hash) in names {
+            writeln!(file, "{:#x},{}", node, hash)?;
         }
+        Ok(())
+    })
+}
+
+// Content hashes are immutable, so a fetched body never goes stale. Each entry
+// is a body file plus a json file with the content type, name, whether the
+// body was truncated and the limit it was read under. The json is written
+// last, so an entry without one is incomplete, and one we can't parse was
+// written by an older version; both count as a miss. Files are named by a
+// digest of the key: keys are case sensitive base58/base64url with scheme
+// prefixes, which don't map onto file names on every filesystem without
+// collisions.
+fn cache_paths(dir: &Path, key: &str) -> (PathBuf, PathBuf) {
+    let name = hex::encode(ethers::utils::keccak256(key.as_bytes()));
+    (dir.join(format!("{}.body", name)), dir.join(format!("{}.json", name)))
+}
 
-        Ok(lines)
-    } else {
-        println!("Could not find {}", filename);
-        Err(Error::new(io::ErrorKind::Other, "Could not find file for events"))
+pub fn load_cached(dir: &Path, key: &str) -> io::Result<Option<Fetched>> {
+    let (body_path, meta_path) = cache_paths(dir, key);
+    if !meta_path.exists() {
+        return Ok(None);
     }
+    let reader = io::BufReader::new(File::open(meta_path)?);
+    let mut fetched: Fetched = match serde_json::from_reader(reader) {
+        Ok(fetched) => fetched,
+        Err(_) => return Ok(None),
+    };
+    fetched.body = fs::read(body_path)?.into();
+    Ok(Some(fetched))
 }
 
-pub fn save_events(path: &str, events: HashSet<String>) -> Result<(),Error> {
-    // Open a file in write-only mode, returns `io::Result<File>`
-    let mut file = File::create(path)?;
+pub fn save_cached(dir: &Path, key: &str, fetched: &Fetched) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let (body_path, meta_path) = cache_paths(dir, key);
+    write_atomic(&body_path, |file| file.write_all(&fetched.body))?;
+    let meta = serde_json::to_vec(fetched).map_err(Error::other)?;
+    write_atomic(&meta_path, |file| file.write_all(&meta))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_manifest_rejects_other_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("manifest.json");
+        assert!(load_manifest(&path).unwrap().is_none());
+
+        let manifest = Manifest { schema_version: SCHEMA_VERSION, segments: Vec::new(), last_block: Some(7), analyzer: "en".to_string(), alt_texts: None };
+        save_manifest(&path, &manifest).unwrap();
+        assert_eq!(load_manifest(&path).unwrap().unwrap().last_block, Some(7));
 
-    // Iterate over each line in the vector
-    for line in events {
-        // Write the line to the file and add a newline character
-        file.write_fmt(format_args!("{}\n", line)).expect("Unable to write to file");
-        writeln!(file, "{}", line)?;
+        fs::write(&path, r#"{"schema_version": 1, "segments": []}"#).unwrap();
+        let e = load_manifest(&path).unwrap_err();
+        assert!(e.to_string().contains("schema version 1"), "{}", e);
+
+        fs::write(&path, r#"{"segments": []}"#).unwrap();
+        assert!(load_manifest(&path).unwrap_err().to_string().contains("no schema version"));
+
+        fs::write(&path, &b"{\"schema_version\": 2, \"segm"[..]).unwrap();
+        assert!(load_manifest(&path).is_err());
     }
-    Ok(())
-}
 
+    #[test]
+    fn load_deletions_rejects_bad_checksums() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1.1.del");
+        let deletions = Deletions::from_bytes(vec![0b0000_0100], 3);
+        let checksum = save_deletions(&path, &deletions).unwrap();
+        assert!(load_deletions(&path, checksum, 3).unwrap().contains(2));
+        assert!(load_deletions(&path, checksum ^ 1, 3).is_err());
+        // the wrong size for the segment
+        assert!(load_deletions(&path, checksum, 20).is_err());
+    }
+}
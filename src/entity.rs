@@ -0,0 +1,144 @@
+use std::sync::OnceLock;
+use ethers::types::Address;
+use ethers::utils::to_checksum;
+use regex::Regex;
+use crate::chain;
+use crate::extract;
+
+// An address, transaction hash, ENS name, ticker or CID found in text. The
+// analyzer would split, stem or lowercase these into something unsearchable,
+// so they get their own fields with terms that are already normalized.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entity {
+    pub(crate) field: &'static str,
+    // what gets indexed and looked up
+    pub(crate) term: String,
+    // how it's shown, e.g. the checksummed address
+    pub(crate) display: String,
+}
+
+fn hex_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\b0x(?:[0-9a-fA-F]{64}|[0-9a-fA-F]{40})\b").unwrap())
+}
+
+fn ens_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"(?i)\b(?:[\p{L}\p{N}_-]+\.)+eth\b").unwrap())
+}
+
+fn ticker_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    // $ followed by a letter, so prices like $100 aren't tickers
+    RE.get_or_init(|| Regex::new(r"(?:^|[^\w$])\$([A-Za-z][A-Za-z0-9]{1,9})\b").unwrap())
+}
+
+fn cid_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    // v0, and v1 in the default base32
+    RE.get_or_init(|| Regex::new(r"\b(?:Qm[1-9A-HJ-NP-Za-km-z]{44}|b[a-z2-7]{58,})\b").unwrap())
+}
+
+fn hex_entity(hex: &str) -> Entity {
+    let term = hex.to_ascii_lowercase();
+    match term.parse::<Address>() {
+        Ok(address) => Entity { field: extract::ADDRESS, display: to_checksum(&address, None), term },
+        Err(_) => Entity { field: extract::TX_HASH, display: term.clone(), term },
+    }
+}
+
+fn ens_entity(name: &str) -> Entity {
+    let term = name.to_lowercase();
+    Entity { field: extract::ENS_NAME, display: term.clone(), term }
+}
+
+fn ticker_entity(symbol: &str) -> Entity {
+    Entity { field: extract::TICKER, term: symbol.to_lowercase(), display: format!("${}", symbol.to_uppercase()) }
+}
+
+// CIDs are matched in their base32 v1 form, so Qm... and bafy... both find it
+fn cid_entity(cid: &str) -> Option<Entity> {
+    let term = chain::normalize_cid(cid)?;
+    Some(Entity { field: extract::CID, display: cid.to_string(), term })
+}
+
+// Every entity in the text, in the order they appear
+pub(crate) fn find(text: &str) -> Vec<Entity> {
+    let mut found: Vec<(usize, Entity)> = Vec::new();
+    found.extend(hex_regex().find_iter(text).map(|m| (m.start(), hex_entity(m.as_str()))));
+    found.extend(ens_regex().find_iter(text).map(|m| (m.start(), ens_entity(m.as_str()))));
+    found.extend(ticker_regex().captures_iter(text).filter_map(|c| {
+        let symbol = c.get(1)?;
+        Some((symbol.start(), ticker_entity(symbol.as_str())))
+    }));
+    found.extend(cid_regex().find_iter(text).filter_map(|m| Some((m.start(), cid_entity(m.as_str())?))));
+    found.sort_by_key(|(start, _)| *start);
+    found.into_iter().map(|(_, entity)| entity).collect()
+}
+
+// The entity a query word names, if it is one
+pub(crate) fn parse(word: &str) -> Option<Entity> {
+    find(word).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V0: &str = "QmRAQB6YaCyidP37UdDnjFY5vQuiBrcqdyoW1CuDgwxkD4";
+    const V1: &str = "bafybeibj6lixxzqtsb45ysdjnupvqkufgdvzqbnvmhw2kf7cfkesy7r7d4";
+    const ADDRESS: &str = "0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045";
+
+    fn fields_and_terms(text: &str) -> Vec<(&'static str, String)> {
+        find(text).into_iter().map(|entity| (entity.field, entity.term)).collect()
+    }
+
+    #[test]
+    fn finds_entities_in_order() {
+        let tx = "0x5c504ed432cb51138bcf09aa5e8a410dd4a1e204ef84bfed1be16dfba1b22060";
+        let text = format!("Send $eth to Vitalik.ETH ({}), not $100. Tx {} at ipfs://{}", ADDRESS.to_lowercase(), tx, V0);
+        assert_eq!(fields_and_terms(&text), vec![
+            (extract::TICKER, "eth".to_string()),
+            (extract::ENS_NAME, "vitalik.eth".to_string()),
+            (extract::ADDRESS, ADDRESS.to_lowercase()),
+            (extract::TX_HASH, tx.to_string()),
+            (extract::CID, V1.to_string()),
+        ]);
+        let displays: Vec<String> = find(&text).into_iter().map(|entity| entity.display).collect();
+        assert_eq!(displays[0], "$ETH");
+        assert_eq!(displays[2], ADDRESS);
+    }
+
+    #[test]
+    fn prices_are_not_tickers() {
+        assert!(find("only $100 or $5.99, US$20").is_empty());
+        assert!(parse("$100").is_none());
+        assert_eq!(parse("$UNI").unwrap().term, "uni");
+    }
+
+    #[test]
+    fn addresses_match_in_any_case() {
+        let upper = format!("0x{}", ADDRESS[2..].to_uppercase());
+        for written in [ADDRESS.to_string(), ADDRESS.to_lowercase(), upper] {
+            let entity = parse(&written).unwrap();
+            assert_eq!(entity.field, extract::ADDRESS);
+            assert_eq!(entity.term, ADDRESS.to_lowercase());
+            assert_eq!(entity.display, ADDRESS);
+        }
+        // too short for an address, too long to be part of one
+        assert!(parse("0xd8dA6BF26964aF9D7eEd9e03E53415D37aA9604").is_none());
+        assert!(parse(&format!("{}ab", ADDRESS)).is_none());
+    }
+
+    #[test]
+    fn cid_versions_have_the_same_term() {
+        let v0 = parse(V0).unwrap();
+        let v1 = parse(V1).unwrap();
+        assert_eq!(v0.field, extract::CID);
+        assert_eq!(v0.term, v1.term);
+        // shown the way the document wrote it
+        assert_eq!(v0.display, V0);
+        // base58 that isn't a CID
+        assert!(parse("Qm11111111111111111111111111111111111111111111").is_none());
+    }
+}
@@ -0,0 +1,228 @@
+use scraper::{ElementRef, Html, Node, Selector};
+use crate::extract::{self, Extracted, Extractor, Input};
+
+// Elements whose text never shows up on the page
+const INVISIBLE: &[&str] = &[
+    "script", "style", "noscript", "template", "svg", "math", "iframe", "object",
+    "embed", "canvas", "head", "title", "meta", "link", "select", "option",
+];
+
+// Navigation and footers repeat on every page of a site and say little about it
+const BOILERPLATE: &[&str] = &["nav", "footer", "aside"];
+const BOILERPLATE_ROLES: &[&str] = &["navigation", "contentinfo", "banner", "complementary"];
+
+fn is_hidden(element: ElementRef) -> bool {
+    let attrs = element.value();
+    INVISIBLE.contains(&attrs.name())
+        || attrs.attr("hidden").is_some()
+        || attrs.attr("aria-hidden").is_some_and(|v| v.eq_ignore_ascii_case("true"))
+        || attrs.attr("style").is_some_and(|style| {
+            let style: String = style.chars().filter(|c| !c.is_whitespace()).collect();
+            let style = style.to_ascii_lowercase();
+            style.contains("display:none") || style.contains("visibility:hidden")
+        })
+}
+
+fn is_boilerplate(element: ElementRef) -> bool {
+    let attrs = element.value();
+    BOILERPLATE.contains(&attrs.name())
+        || attrs.attr("role").is_some_and(|role| BOILERPLATE_ROLES.contains(&role.to_ascii_lowercase().as_str()))
+}
+
+// Collects the text a reader would see, one fragment per text node. Text in
+// boilerplate goes into its own list so the caller can decide whether to keep it.
+// Walks with an explicit stack, since pages can nest deeply enough to overflow
+// the call stack.
+fn visible_text(element: ElementRef, text: &mut Vec<String>, boilerplate: &mut Vec<String>) {
+    // Children go on in reverse so they come off in document order
+    let mut stack: Vec<_> = element.children().rev().map(|child| (child, false)).collect();
+    while let Some((node, in_boilerplate)) = stack.pop() {
+        match node.value() {
+            Node::Text(t) => {
+                let fragment = extract::collapse_whitespace(t);
+                if fragment.is_empty() {
+                    continue;
+                }
+                if in_boilerplate {
+                    boilerplate.push(fragment);
+                } else {
+                    text.push(fragment);
+                }
+            }
+            Node::Element(_) => {
+                let Some(child) = ElementRef::wrap(node) else { continue };
+                if is_hidden(child) {
+                    continue;
+                }
+                let in_boilerplate = in_boilerplate || is_boilerplate(child);
+                stack.extend(node.children().rev().map(|child| (child, in_boilerplate)));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn element_text(element: ElementRef) -> String {
+    element.text().collect::<Vec<_>>().join(" ")
+}
+
+// <title>, description, keywords and Open Graph tags, and the canonical url
+fn extract_head(document: &Html, extracted: &mut Extracted) {
+    let title = Selector::parse("title").unwrap();
+    if let Some(element) = document.select(&title).next() {
+        extracted.set_field(extract::TITLE, &element_text(element));
+    }
+
+    let meta = Selector::parse("meta[content]").unwrap();
+    for element in document.select(&meta) {
+        let attrs = element.value();
+        // Open Graph uses property, but plenty of sites put it in name
+        let key = attrs.attr("name").or_else(|| attrs.attr("property")).unwrap_or("");
+        let field = match key.trim().to_ascii_lowercase().as_str() {
+            "description" => extract::DESCRIPTION,
+            "keywords" => extract::KEYWORDS,
+            "og:title" => extract::OG_TITLE,
+            "og:description" => extract::OG_DESCRIPTION,
+            _ => continue,
+        };
+        extracted.set_field(field, attrs.attr("content").unwrap_or(""));
+    }
+
+    let link = Selector::parse("link[rel][href]").unwrap();
+    for element in document.select(&link) {
+        let attrs = element.value();
+        let is_canonical = attrs
+            .attr("rel")
+            .is_some_and(|rel| rel.split_whitespace().any(|r| r.eq_ignore_ascii_case("canonical")));
+        if is_canonical {
+            extracted.set_field(extract::CANONICAL, attrs.attr("href").unwrap_or(""));
+        }
+    }
+
+    // Comma separated in practice, spaces are enough for indexing
+    if let Some(keywords) = extracted.fields.get_mut(extract::KEYWORDS) {
+        *keywords = extract::collapse_whitespace(&keywords.replace(',', " "));
+    }
+}
+
+// Fewer visible words than this and a page with scripts is probably rendered client side
+const APP_SHELL_MAX_WORDS: usize = 20;
+
+// The files a client side app loads its content from
+#[derive(Debug, Default)]
+pub(crate) struct Assets {
+    pub(crate) scripts: Vec<String>,
+    pub(crate) manifest: Option<String>,
+}
+
+// Whether the page is an empty shell like <div id="root"></div> that only
+// gets content once its javascript runs
+pub(crate) fn is_app_shell(body: &str) -> bool {
+    let document = Html::parse_document(body);
+    let script = Selector::parse("script").unwrap();
+    if document.select(&script).next().is_none() {
+        return false;
+    }
+    let words: usize = extract_document(&document)
+        .text
+        .iter()
+        .map(|fragment| fragment.split_whitespace().count())
+        .sum();
+    words < APP_SHELL_MAX_WORDS
+}
+
+pub(crate) fn assets(body: &str) -> Assets {
+    let document = Html::parse_document(body);
+    let script = Selector::parse("script[src]").unwrap();
+    let manifest = Selector::parse("link[rel~=manifest][href]").unwrap();
+    Assets {
+        scripts: document
+            .select(&script)
+            .filter_map(|element| element.value().attr("src"))
+            .map(String::from)
+            .collect(),
+        manifest: document
+            .select(&manifest)
+            .next()
+            .and_then(|element| element.value().attr("href"))
+            .map(String::from),
+    }
+}
+
+// The CID an image source points at, if it's a whole ipfs object rather than
+// a file inside one: ipfs://CID, a gateway's /ipfs/CID or a CID.ipfs.host subdomain
+fn referenced_cid(src: &str) -> Option<&str> {
+    let src = src.trim();
+    let (cid, rest) = if let Some(rest) = src.strip_prefix("ipfs://") {
+        rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()))
+    } else if let Some(at) = src.find("/ipfs/") {
+        let rest = &src[at + "/ipfs/".len()..];
+        rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()))
+    } else {
+        let host = src.split("://").nth(1)?;
+        let (host, rest) = host.split_at(host.find(['/', '?', '#']).unwrap_or(host.len()));
+        (host.split_once(".ipfs.")?.0, rest)
+    };
+    let path = rest.split(['?', '#']).next().unwrap_or("");
+    (!cid.is_empty() && (path.is_empty() || path == "/")).then_some(cid)
+}
+
+// Alt text of images that are themselves content hashes, so an image that's
+// an ENS site of its own can be found by how others describe it
+fn extract_alt_texts(document: &Html, extracted: &mut Extracted) {
+    let img = Selector::parse("img[alt][src]").unwrap();
+    for element in document.select(&img) {
+        let attrs = element.value();
+        let alt = extract::collapse_whitespace(attrs.attr("alt").unwrap_or(""));
+        if alt.is_empty() {
+            continue;
+        }
+        if let Some(cid) = attrs.attr("src").and_then(referenced_cid) {
+            extracted.alt_texts.push((cid.to_string(), alt));
+        }
+    }
+}
+
+pub(crate) fn extract(body: &str) -> Extracted {
+    extract_document(&Html::parse_document(body))
+}
+
+fn extract_document(document: &Html) -> Extracted {
+    let mut extracted = Extracted::default();
+    extract_head(document, &mut extracted);
+    extract_alt_texts(document, &mut extracted);
+
+    let mut text = Vec::new();
+    let mut boilerplate = Vec::new();
+    let selector = Selector::parse("body").unwrap();
+    for element in document.select(&selector) {
+        visible_text(element, &mut text, &mut boilerplate);
+    }
+    // Some pages are nothing but a menu; that's still better than nothing
+    if text.is_empty() {
+        text = boilerplate;
+    } else {
+        boilerplate.dedup();
+        if !boilerplate.is_empty() {
+            extracted.set_field(extract::BOILERPLATE, &boilerplate.join(" "));
+        }
+    }
+    extracted.text = text;
+    extracted
+}
+
+pub(crate) struct HtmlExtractor;
+
+impl Extractor for HtmlExtractor {
+    fn name(&self) -> &'static str {
+        "html"
+    }
+
+    fn handles(&self, content_type: &str) -> bool {
+        matches!(content_type, "text/html" | "application/xhtml+xml")
+    }
+
+    fn extract(&self, input: &Input) -> Option<Extracted> {
+        Some(extract(&input.text()))
+    }
+}
@@ -0,0 +1,104 @@
+use serde_json::Value;
+use crate::extract::{self, Extracted, Extractor, Input};
+
+// Bounds for arbitrary json, which can be nested deeply or have a field per token id
+const MAX_DEPTH: usize = 16;
+const MAX_FIELDS: usize = 1000;
+// Paths go under their own namespace so a key can't land in a built-in field
+// like title, lang or ticker
+const FIELD_PREFIX: &str = "json.";
+
+// Field names double as query prefixes (json.name:foo), which only take
+// letters, digits, _ and the . between keys, so anything else in a key
+// becomes _. A . inside a key would read as a nested path.
+fn field_name(key: &str) -> String {
+    let name: String = key
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if name.is_empty() { "_".to_string() } else { name }
+}
+
+fn walk(value: &Value, path: &mut Vec<String>, extracted: &mut Extracted) {
+    match value {
+        Value::String(text) if path.is_empty() => extracted.text.push(extract::collapse_whitespace(text)),
+        Value::String(text) => {
+            let field = format!("{}{}", FIELD_PREFIX, path.join("."));
+            if extracted.fields.contains_key(&field) || extracted.fields.len() < MAX_FIELDS {
+                extracted.append_field(&field, text);
+            }
+        }
+        // Array items share their parent's path, so every attributes[i].value is attributes.value
+        Value::Array(items) => {
+            for item in items {
+                walk(item, path, extracted);
+            }
+        }
+        Value::Object(map) if path.len() < MAX_DEPTH => {
+            for (key, value) in map {
+                path.push(field_name(key));
+                walk(value, path, extracted);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+// Every string value becomes part of a field named by its path, e.g. json.name,
+// json.description and json.attributes.value for NFT metadata. A top level name or
+// title is also the document's title. Returns None if the body isn't json.
+pub(crate) fn extract(body: &str) -> Option<Extracted> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    let mut extracted = Extracted::default();
+    walk(&value, &mut Vec::new(), &mut extracted);
+
+    let title = ["name", "title"].iter().find_map(|key| value.get(*key).and_then(|v| v.as_str()));
+    if let Some(title) = title {
+        extracted.set_field(extract::TITLE, title);
+    }
+    Some(extracted)
+}
+
+pub(crate) struct JsonExtractor;
+
+impl Extractor for JsonExtractor {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn handles(&self, content_type: &str) -> bool {
+        content_type == "application/json"
+    }
+
+    fn extract(&self, input: &Input) -> Option<Extracted> {
+        extract(&input.text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_become_queryable_field_names() {
+        let body = r#"{"name": "Gold #1", "external-url": "https://x.eth", "@context": "schema",
+            "a.b": "dotted", "a": {"b": "nested"}, "Trait Type": "rare", "": "blank",
+            "attributes": [{"value": "gold"}, {"value": "round"}]}"#;
+        let extracted = extract(body).unwrap();
+        let field = |name: &str| extracted.fields.get(name).map(String::as_str);
+        assert_eq!(field(extract::TITLE), Some("Gold #1"));
+        assert_eq!(field("json.external_url"), Some("https://x.eth"));
+        assert_eq!(field("json._context"), Some("schema"));
+        assert_eq!(field("json.a_b"), Some("dotted"));
+        assert_eq!(field("json.a.b"), Some("nested"));
+        assert_eq!(field("json.trait_type"), Some("rare"));
+        assert_eq!(field("json._"), Some("blank"));
+        assert!(field("json.attributes.value").is_some_and(|value| value.contains("gold") && value.contains("round")));
+        for name in extracted.fields.keys() {
+            assert!(name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.'), "{}", name);
+        }
+    }
+}
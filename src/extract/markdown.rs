@@ -0,0 +1,49 @@
+use pulldown_cmark::{Event, HeadingLevel, Parser, Tag, TagEnd};
+use crate::extract::{self, Extracted, Extractor, Input};
+
+// Text per block (paragraph, heading, list item, code block), with the first
+// top level heading as the title
+pub(crate) fn extract(body: &str) -> Extracted {
+    let mut extracted = Extracted::default();
+    let mut block = String::new();
+    let mut in_title = false;
+
+    for event in Parser::new(body) {
+        match event {
+            Event::Start(Tag::Heading { level: HeadingLevel::H1, .. }) => in_title = true,
+            Event::Text(text) | Event::Code(text) => {
+                block.push_str(&text);
+            }
+            Event::SoftBreak | Event::HardBreak => block.push(' '),
+            Event::End(TagEnd::Heading(_) | TagEnd::Paragraph | TagEnd::Item | TagEnd::CodeBlock | TagEnd::TableCell) => {
+                if in_title {
+                    extracted.set_field(extract::TITLE, &block);
+                    in_title = false;
+                }
+                let fragment = extract::collapse_whitespace(&block);
+                if !fragment.is_empty() {
+                    extracted.text.push(fragment);
+                }
+                block.clear();
+            }
+            _ => {}
+        }
+    }
+    extracted
+}
+
+pub(crate) struct MarkdownExtractor;
+
+impl Extractor for MarkdownExtractor {
+    fn name(&self) -> &'static str {
+        "markdown"
+    }
+
+    fn handles(&self, content_type: &str) -> bool {
+        matches!(content_type, "text/markdown" | "text/x-markdown")
+    }
+
+    fn extract(&self, input: &Input) -> Option<Extracted> {
+        Some(extract(&input.text()))
+    }
+}
@@ -0,0 +1,279 @@
+use std::io::Cursor;
+use exif::{Context, In, Tag, Value};
+use crate::extract::{self, Extracted, Extractor, Input};
+
+// Only the first MAX_BINARY_BYTES of media are fetched, so every reader here
+// has to cope with a body that stops in the middle of a structure.
+
+// Windows "XP" tags, which kamadak-exif doesn't name
+const XP_TITLE: Tag = Tag(Context::Tiff, 0x9c9b);
+const XP_COMMENT: Tag = Tag(Context::Tiff, 0x9c9c);
+const XP_KEYWORDS: Tag = Tag(Context::Tiff, 0x9c9e);
+const XP_SUBJECT: Tag = Tag(Context::Tiff, 0x9c9f);
+
+fn be_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn le_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn utf16le(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    String::from_utf16_lossy(&units).trim_end_matches('\0').to_string()
+}
+
+fn exif_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Ascii(parts) => Some(
+            parts.iter().map(|p| String::from_utf8_lossy(p).into_owned()).collect::<Vec<_>>().join(" "),
+        ),
+        // XP tags are utf-16 in a byte array
+        Value::Byte(bytes) => Some(utf16le(bytes)),
+        // UserComment: an 8 byte charset id, then the text
+        Value::Undefined(bytes, _) if bytes.len() > 8 => match &bytes[..8] {
+            b"UNICODE\0" => Some(utf16le(&bytes[8..])),
+            _ => Some(String::from_utf8_lossy(&bytes[8..]).trim_end_matches('\0').to_string()),
+        },
+        _ => None,
+    }
+}
+
+fn extract_exif(body: &[u8], extracted: &mut Extracted) {
+    let Ok(exif) = exif::Reader::new().read_from_container(&mut Cursor::new(body)) else { return };
+    let fields = [
+        (XP_TITLE, extract::TITLE),
+        (Tag::ImageDescription, extract::DESCRIPTION),
+        (XP_SUBJECT, extract::DESCRIPTION),
+        (XP_COMMENT, extract::DESCRIPTION),
+        (Tag::UserComment, extract::DESCRIPTION),
+        (Tag::Artist, extract::ARTIST),
+        (XP_KEYWORDS, extract::KEYWORDS),
+    ];
+    for (tag, field) in fields {
+        let value = exif.get_field(tag, In::PRIMARY).and_then(|f| exif_text(&f.value));
+        if let Some(value) = value {
+            extracted.set_field(field, &value);
+        }
+    }
+}
+
+// tEXt and iTXt chunks, which is where most tools put a png's title and description
+fn extract_png_text(body: &[u8], extracted: &mut Extracted) {
+    let mut at = 8;
+    while let (Some(length), Some(kind)) = (be_u32(body, at), body.get(at + 4..at + 8)) {
+        let data = body.get(at + 8..at + 8 + length as usize);
+        at += 12 + length as usize;
+        let Some(data) = data else { break };
+        let text = match kind {
+            b"tEXt" => data.splitn(2, |b| *b == 0).collect::<Vec<_>>(),
+            // keyword, compression flag and method, language, translated keyword, text
+            b"iTXt" => {
+                let mut parts = data.splitn(2, |b| *b == 0);
+                let keyword = parts.next().unwrap_or(&[]);
+                let rest = parts.next().unwrap_or(&[]);
+                if rest.first() != Some(&0) {
+                    continue;
+                }
+                let text = rest.get(2..).unwrap_or(&[]).splitn(3, |b| *b == 0).nth(2).unwrap_or(&[]);
+                vec![keyword, text]
+            }
+            b"IEND" => break,
+            _ => continue,
+        };
+        if let [keyword, value] = text[..] {
+            let value = String::from_utf8_lossy(value);
+            match keyword {
+                b"Title" => extracted.set_field(extract::TITLE, &value),
+                b"Description" | b"Comment" => extracted.set_field(extract::DESCRIPTION, &value),
+                b"Author" => extracted.set_field(extract::ARTIST, &value),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn id3_text(frame: &[u8]) -> String {
+    let (encoding, text) = match frame.split_first() {
+        Some((encoding, text)) => (*encoding, text),
+        None => return String::new(),
+    };
+    let text = match encoding {
+        // utf-16 with a byte order mark, or utf-16be without
+        1 | 2 => {
+            let big_endian = encoding == 2 || text.starts_with(&[0xfe, 0xff]);
+            let text = text.strip_prefix(&[0xfe, 0xff]).or_else(|| text.strip_prefix(&[0xff, 0xfe])).unwrap_or(text);
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|c| if big_endian { u16::from_be_bytes([c[0], c[1]]) } else { u16::from_le_bytes([c[0], c[1]]) })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        3 => String::from_utf8_lossy(text).into_owned(),
+        _ => text.iter().map(|b| *b as char).collect(),
+    };
+    text.replace('\0', " ")
+}
+
+fn synchsafe(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |acc, b| (acc << 7) | (*b as usize & 0x7f))
+}
+
+// ID3v2.3 and 2.4 text frames
+fn extract_id3(body: &[u8], extracted: &mut Extracted) {
+    let Some(header) = body.get(..10).filter(|h| h.starts_with(b"ID3")) else { return };
+    let version = header[3];
+    let end = (10 + synchsafe(&header[6..10])).min(body.len());
+    let mut at = 10;
+    while let Some(frame_header) = body.get(at..at + 10).filter(|_| at + 10 <= end) {
+        let id = &frame_header[..4];
+        if id[0] == 0 {
+            break;
+        }
+        let size = if version >= 4 { synchsafe(&frame_header[4..8]) } else { be_u32(frame_header, 4).unwrap_or(0) as usize };
+        let frame = body.get(at + 10..(at + 10 + size).min(body.len())).unwrap_or(&[]);
+        at += 10 + size;
+        let field = match id {
+            b"TIT2" => extract::TITLE,
+            b"TPE1" | b"TPE2" => extract::ARTIST,
+            b"TALB" => extract::ALBUM,
+            b"COMM" => {
+                // encoding, language, short description, text
+                let Some(encoding) = frame.first() else { continue };
+                let rest = frame.get(4..).unwrap_or(&[]);
+                let text = match encoding {
+                    1 | 2 => rest.windows(2).position(|w| w == [0, 0]).map(|i| &rest[i + 2..]),
+                    _ => rest.iter().position(|b| *b == 0).map(|i| &rest[i + 1..]),
+                };
+                let mut comment = vec![*encoding];
+                comment.extend_from_slice(text.unwrap_or(&[]));
+                extracted.set_field(extract::DESCRIPTION, &id3_text(&comment));
+                continue;
+            }
+            _ => continue,
+        };
+        extracted.set_field(field, &id3_text(frame));
+    }
+}
+
+// Vorbis comments, as used by flac, ogg vorbis and opus: KEY=value pairs
+fn vorbis_comments(block: &[u8], extracted: &mut Extracted) {
+    let Some(vendor_len) = le_u32(block, 0) else { return };
+    let mut at = 4 + vendor_len as usize;
+    let Some(count) = le_u32(block, at) else { return };
+    at += 4;
+    for _ in 0..count {
+        let Some(len) = le_u32(block, at) else { return };
+        let Some(comment) = block.get(at + 4..at + 4 + len as usize) else { return };
+        at += 4 + len as usize;
+        let comment = String::from_utf8_lossy(comment);
+        let Some((key, value)) = comment.split_once('=') else { continue };
+        let field = match key.to_ascii_uppercase().as_str() {
+            "TITLE" => extract::TITLE,
+            "ARTIST" | "ALBUMARTIST" => extract::ARTIST,
+            "ALBUM" => extract::ALBUM,
+            "DESCRIPTION" | "COMMENT" => extract::DESCRIPTION,
+            _ => continue,
+        };
+        extracted.set_field(field, value);
+    }
+}
+
+fn extract_flac(body: &[u8], extracted: &mut Extracted) {
+    let mut at = 4;
+    while let Some(header) = body.get(at..at + 4) {
+        let last = header[0] & 0x80 != 0;
+        let kind = header[0] & 0x7f;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        if kind == 4 {
+            vorbis_comments(body.get(at + 4..).unwrap_or(&[]), extracted);
+            return;
+        }
+        if last {
+            return;
+        }
+        at += 4 + len;
+    }
+}
+
+fn extract_ogg(body: &[u8], extracted: &mut Extracted) {
+    // The comment header is in the second page; it's small enough that page
+    // boundaries inside it are rare, so just look for its magic
+    for magic in [&b"\x03vorbis"[..], &b"OpusTags"[..]] {
+        if let Some(at) = body.windows(magic.len()).position(|w| w == magic) {
+            vorbis_comments(&body[at + magic.len()..], extracted);
+            return;
+        }
+    }
+}
+
+// iTunes style metadata in moov/udta/meta/ilst
+fn extract_mp4_boxes(body: &[u8], extracted: &mut Extracted) {
+    let mut at = 0;
+    while let (Some(size), Some(kind)) = (be_u32(body, at), body.get(at + 4..at + 8)) {
+        let size = size as usize;
+        if size < 8 {
+            return;
+        }
+        let content = &body[at + 8..(at + size).min(body.len())];
+        match kind {
+            b"moov" | b"udta" | b"ilst" => extract_mp4_boxes(content, extracted),
+            // meta has a version and flags before its children
+            b"meta" => extract_mp4_boxes(content.get(4..).unwrap_or(&[]), extracted),
+            b"\xa9nam" | b"\xa9ART" | b"\xa9alb" | b"desc" | b"\xa9cmt" => {
+                // a data box: size, "data", type, locale, value
+                let Some(value) = content.get(16..) else { return };
+                let field = match kind {
+                    b"\xa9nam" => extract::TITLE,
+                    b"\xa9ART" => extract::ARTIST,
+                    b"\xa9alb" => extract::ALBUM,
+                    _ => extract::DESCRIPTION,
+                };
+                extracted.set_field(field, &String::from_utf8_lossy(value));
+            }
+            _ => {}
+        }
+        at += size;
+    }
+}
+
+// Titles, artists, descriptions and dimensions from image, audio and video
+// headers. Media have no body text, so these fields are all there is to search.
+pub(crate) fn extract(mime: &str, body: &[u8]) -> Extracted {
+    let mut extracted = Extracted::default();
+    match mime {
+        "image/jpeg" | "image/tiff" | "image/webp" | "image/heic" | "image/avif" => extract_exif(body, &mut extracted),
+        "image/png" => {
+            extract_png_text(body, &mut extracted);
+            extract_exif(body, &mut extracted);
+        }
+        "audio/mpeg" => extract_id3(body, &mut extracted),
+        "audio/flac" => extract_flac(body, &mut extracted),
+        "audio/ogg" => extract_ogg(body, &mut extracted),
+        "video/mp4" | "audio/mp4" => extract_mp4_boxes(body, &mut extracted),
+        _ => {}
+    }
+    if mime.starts_with("image/") {
+        if let Ok(size) = imagesize::blob_size(body) {
+            extracted.set_field(extract::DIMENSIONS, &format!("{}x{}", size.width, size.height));
+        }
+    }
+    extracted
+}
+
+pub(crate) struct MediaExtractor;
+
+impl Extractor for MediaExtractor {
+    fn name(&self) -> &'static str {
+        "media"
+    }
+
+    fn handles(&self, content_type: &str) -> bool {
+        ["image/", "audio/", "video/"].iter().any(|prefix| content_type.starts_with(prefix))
+    }
+
+    fn extract(&self, input: &Input) -> Option<Extracted> {
+        Some(extract(input.content_type, input.body))
+    }
+}
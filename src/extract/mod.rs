@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use crate::charset;
+
+pub(crate) mod html;
+pub(crate) mod json;
+pub(crate) mod markdown;
+pub(crate) mod media;
+pub(crate) mod office;
+pub(crate) mod pdf;
+pub(crate) mod text;
+
+// Field names. Everything that isn't a named field is body text.
+pub(crate) const BODY: &str = "body";
+pub(crate) const TITLE: &str = "title";
+pub(crate) const DESCRIPTION: &str = "description";
+pub(crate) const OG_TITLE: &str = "og_title";
+pub(crate) const OG_DESCRIPTION: &str = "og_description";
+pub(crate) const KEYWORDS: &str = "keywords";
+pub(crate) const CANONICAL: &str = "canonical";
+// nav, footer and the like, kept apart from the body
+pub(crate) const BOILERPLATE: &str = "boilerplate";
+// media metadata
+pub(crate) const ARTIST: &str = "artist";
+pub(crate) const ALBUM: &str = "album";
+pub(crate) const DIMENSIONS: &str = "dimensions";
+// alt text of images on other sites that point at this document
+pub(crate) const ALT_TEXT: &str = "alt_text";
+// crypto entities found anywhere in the document, see entity.rs
+pub(crate) const ADDRESS: &str = "address";
+pub(crate) const TX_HASH: &str = "tx";
+pub(crate) const ENS_NAME: &str = "ens";
+pub(crate) const TICKER: &str = "ticker";
+pub(crate) const CID: &str = "cid";
+
+// Fields that are kept with the document but not searchable
+const STORED_ONLY: &[&str] = &[CANONICAL, DIMENSIONS];
+
+// What an extractor pulls out of a document: named fields, the body text as
+// the fragments it was found in, and alt text for the other content hashes
+// the document embeds, as (content hash, alt text)
+#[derive(Debug, Default)]
+pub(crate) struct Extracted {
+    pub(crate) fields: BTreeMap<String, String>,
+    pub(crate) text: Vec<String>,
+    pub(crate) alt_texts: Vec<(String, String)>,
+}
+
+impl Extracted {
+    // Keeps the first value seen for a field, ignoring empty ones
+    pub(crate) fn set_field(&mut self, field: &str, value: &str) {
+        let value = collapse_whitespace(value);
+        if !value.is_empty() {
+            self.fields.entry(field.to_string()).or_insert(value);
+        }
+    }
+
+    // Adds to a field that can have several values, like a json array
+    pub(crate) fn append_field(&mut self, field: &str, value: &str) {
+        let value = collapse_whitespace(value);
+        if value.is_empty() {
+            return;
+        }
+        self.fields
+            .entry(field.to_string())
+            .and_modify(|existing| {
+                existing.push(' ');
+                existing.push_str(&value);
+            })
+            .or_insert(value);
+    }
+}
+
+// A fetched document as extractors see it
+pub(crate) struct Input<'a> {
+    // what the body actually is, after sniffing
+    pub(crate) content_type: &'a str,
+    // what the gateway said it is, which may name the charset
+    pub(crate) header: &'a str,
+    pub(crate) body: &'a [u8],
+}
+
+impl Input<'_> {
+    pub(crate) fn text(&self) -> String {
+        charset::decode(self.header, self.body)
+    }
+}
+
+// Turns one kind of document into fields and text. Returning None passes the
+// document on to the next extractor that handles its content type.
+pub(crate) trait Extractor: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn handles(&self, content_type: &str) -> bool;
+    fn extract(&self, input: &Input) -> Option<Extracted>;
+}
+
+// Extractors are tried newest first, so a registered extractor takes over
+// content types from the built in ones
+pub(crate) struct Registry {
+    extractors: Vec<Box<dyn Extractor>>,
+}
+
+impl Registry {
+    pub(crate) fn empty() -> Self {
+        Registry { extractors: Vec::new() }
+    }
+
+    pub(crate) fn register(&mut self, extractor: Box<dyn Extractor>) -> &mut Self {
+        self.extractors.push(extractor);
+        self
+    }
+
+    pub(crate) fn extract(&self, input: &Input) -> Option<Extracted> {
+        self.extractors
+            .iter()
+            .rev()
+            .filter(|extractor| extractor.handles(input.content_type))
+            .find_map(|extractor| {
+                let extracted = extractor.extract(input);
+                if extracted.is_none() {
+                    println!("{} could not extract {}", extractor.name(), input.content_type);
+                }
+                extracted
+            })
+    }
+}
+
+// Every built in extractor. Plain text goes first so it's the fallback for
+// json that turns out not to be json.
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Registry::empty();
+        registry
+            .register(Box::new(text::TextExtractor))
+            .register(Box::new(json::JsonExtractor))
+            .register(Box::new(html::HtmlExtractor))
+            .register(Box::new(markdown::MarkdownExtractor))
+            .register(Box::new(pdf::PdfExtractor))
+            .register(Box::new(office::OfficeExtractor))
+            .register(Box::new(media::MediaExtractor));
+        registry
+    }
+}
+
+pub(crate) fn is_indexed(field: &str) -> bool {
+    !STORED_ONLY.contains(&field)
+}
+
+// How much a match in each field counts towards a document's score
+pub(crate) fn field_boost(field: &str) -> f32 {
+    match field {
+        TITLE => 5.0,
+        OG_TITLE => 4.0,
+        DESCRIPTION | KEYWORDS | ARTIST | ALT_TEXT => 3.0,
+        OG_DESCRIPTION => 2.0,
+        BOILERPLATE => 0.2,
+        _ => 1.0,
+    }
+}
+
+pub(crate) fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
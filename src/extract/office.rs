@@ -0,0 +1,127 @@
+use std::io::{Cursor, Read};
+use std::sync::OnceLock;
+use regex::Regex;
+use zip::ZipArchive;
+use crate::extract::{self, html, Extracted, Extractor, Input};
+use crate::sniff::{DOCX, EPUB, ODT};
+
+type Archive<'a> = ZipArchive<Cursor<&'a [u8]>>;
+
+// Anything bigger than this inside an archive isn't text we want
+const MAX_ENTRY_BYTES: u64 = 16 * 1024 * 1024;
+
+fn tag_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"<[^>]*>").unwrap())
+}
+
+fn title_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"(?s)<dc:title[^>]*>(.*?)</dc:title>").unwrap())
+}
+
+fn read_entry(archive: &mut Archive, name: &str) -> Option<String> {
+    let entry = archive.by_name(name).ok()?;
+    let mut text = String::new();
+    entry.take(MAX_ENTRY_BYTES).read_to_string(&mut text).ok()?;
+    Some(text)
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// The text of an xml document, one fragment per element named in paragraph_ends
+fn xml_text(xml: &str, paragraph_ends: &[&str]) -> Vec<String> {
+    let mut xml = xml.to_string();
+    for end in paragraph_ends {
+        xml = xml.replace(end, "\n");
+    }
+    tag_regex()
+        .replace_all(&xml, "")
+        .lines()
+        .map(|line| extract::collapse_whitespace(&unescape_xml(line)))
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+// Dublin core title, as used by docx core properties, odt meta.xml and epub packages
+fn dc_title(xml: &str) -> Option<String> {
+    let title = title_regex().captures(xml)?.get(1)?.as_str();
+    Some(unescape_xml(&tag_regex().replace_all(title, "")))
+}
+
+fn extract_docx(archive: &mut Archive, extracted: &mut Extracted) {
+    if let Some(title) = read_entry(archive, "docProps/core.xml").and_then(|xml| dc_title(&xml)) {
+        extracted.set_field(extract::TITLE, &title);
+    }
+    if let Some(xml) = read_entry(archive, "word/document.xml") {
+        extracted.text = xml_text(&xml, &["</w:p>", "<w:tab/>", "<w:br/>"]);
+    }
+}
+
+fn extract_odt(archive: &mut Archive, extracted: &mut Extracted) {
+    if let Some(title) = read_entry(archive, "meta.xml").and_then(|xml| dc_title(&xml)) {
+        extracted.set_field(extract::TITLE, &title);
+    }
+    if let Some(xml) = read_entry(archive, "content.xml") {
+        extracted.text = xml_text(&xml, &["</text:p>", "</text:h>", "<text:tab/>", "<text:line-break/>"]);
+    }
+}
+
+// Chapters are xhtml, so each one goes through the html extractor, in the
+// order they are stored (which is reading order for every epub we've seen)
+fn extract_epub(archive: &mut Archive, extracted: &mut Extracted) {
+    let names: Vec<String> = archive.file_names().map(String::from).collect();
+    if let Some(opf) = names.iter().find(|name| name.ends_with(".opf")) {
+        if let Some(title) = read_entry(archive, opf).and_then(|xml| dc_title(&xml)) {
+            extracted.set_field(extract::TITLE, &title);
+        }
+    }
+    let chapters = names
+        .iter()
+        .filter(|name| name.ends_with(".xhtml") || name.ends_with(".html") || name.ends_with(".htm"));
+    for chapter in chapters {
+        if let Some(page) = read_entry(archive, chapter) {
+            extracted.text.extend(html::extract(&page).text);
+        }
+    }
+}
+
+pub(crate) fn extract(mime: &str, body: &[u8]) -> Extracted {
+    let mut extracted = Extracted::default();
+    let mut archive = match ZipArchive::new(Cursor::new(body)) {
+        Ok(archive) => archive,
+        Err(e) => {
+            println!("Could not open {} archive: {}", mime, e);
+            return extracted;
+        }
+    };
+    match mime {
+        DOCX => extract_docx(&mut archive, &mut extracted),
+        ODT => extract_odt(&mut archive, &mut extracted),
+        EPUB => extract_epub(&mut archive, &mut extracted),
+        _ => {}
+    }
+    extracted
+}
+
+pub(crate) struct OfficeExtractor;
+
+impl Extractor for OfficeExtractor {
+    fn name(&self) -> &'static str {
+        "office"
+    }
+
+    fn handles(&self, content_type: &str) -> bool {
+        matches!(content_type, DOCX | ODT | EPUB)
+    }
+
+    fn extract(&self, input: &Input) -> Option<Extracted> {
+        Some(extract(input.content_type, input.body))
+    }
+}
@@ -0,0 +1,76 @@
+use std::panic;
+use pdf_extract::Document;
+use crate::extract::{self, Extracted, Extractor, Input};
+
+// PDF strings are either UTF-16BE with a byte order mark or PDFDocEncoding,
+// which matches latin-1 for everything that matters here
+fn decode_pdf_string(bytes: &[u8]) -> String {
+    match bytes.strip_prefix(&[0xfe, 0xff]) {
+        Some(utf16) => {
+            let units: Vec<u16> = utf16.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        None => bytes.iter().map(|b| *b as char).collect(),
+    }
+}
+
+fn title(document: &Document) -> Option<String> {
+    let info = document.trailer.get(b"Info").ok()?;
+    let info = match info.as_reference() {
+        Ok(id) => document.get_object(id).ok()?.as_dict().ok()?,
+        Err(_) => info.as_dict().ok()?,
+    };
+    let title = info.get(b"Title").ok()?.as_str().ok()?;
+    Some(decode_pdf_string(title))
+}
+
+// The text layer, one fragment per paragraph. Scanned PDFs without a text
+// layer come out empty.
+pub(crate) fn extract(body: &[u8]) -> Extracted {
+    let mut extracted = Extracted::default();
+    // pdf-extract panics on some malformed files rather than returning an error
+    let result = panic::catch_unwind(|| {
+        let title = Document::load_mem(body).ok().and_then(|document| title(&document));
+        (title, pdf_extract::extract_text_from_mem_by_pages(body))
+    });
+    let (title, pages) = match result {
+        Ok(result) => result,
+        Err(_) => {
+            println!("PDF extraction panicked");
+            return extracted;
+        }
+    };
+
+    if let Some(title) = title {
+        extracted.set_field(extract::TITLE, &title);
+    }
+    match pages {
+        Ok(pages) => {
+            for page in pages {
+                let paragraphs = page
+                    .split("\n\n")
+                    .map(extract::collapse_whitespace)
+                    .filter(|paragraph| !paragraph.is_empty());
+                extracted.text.extend(paragraphs);
+            }
+        }
+        Err(e) => println!("Could not extract text from PDF: {}", e),
+    }
+    extracted
+}
+
+pub(crate) struct PdfExtractor;
+
+impl Extractor for PdfExtractor {
+    fn name(&self) -> &'static str {
+        "pdf"
+    }
+
+    fn handles(&self, content_type: &str) -> bool {
+        content_type == "application/pdf"
+    }
+
+    fn extract(&self, input: &Input) -> Option<Extracted> {
+        Some(extract(input.body))
+    }
+}
@@ -0,0 +1,31 @@
+use crate::extract::{self, Extracted, Extractor, Input};
+
+// Plain text has no structure to speak of, so each line is a fragment
+pub(crate) fn extract(body: &str) -> Extracted {
+    Extracted {
+        text: body
+            .lines()
+            .map(extract::collapse_whitespace)
+            .filter(|line| !line.is_empty())
+            .collect(),
+        ..Extracted::default()
+    }
+}
+
+pub(crate) struct TextExtractor;
+
+impl Extractor for TextExtractor {
+    fn name(&self) -> &'static str {
+        "text"
+    }
+
+    // json is here too, for documents that were cut off or aren't json after
+    // all; the words are still worth having
+    fn handles(&self, content_type: &str) -> bool {
+        matches!(content_type, "text/plain" | "text/csv" | "application/json")
+    }
+
+    fn extract(&self, input: &Input) -> Option<Extracted> {
+        Some(extract(&input.text()))
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use crate::config::FailurePolicy;
+
+// Why and when a content hash could not be fetched. Times are unix seconds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct FailureRecord {
+    pub(crate) reason: String,
+    pub(crate) first_failed: u64,
+    pub(crate) last_failed: u64,
+    pub(crate) attempts: u32,
+    pub(crate) next_check: u64,
+}
+
+// failures = content hash -> failure record
+pub(crate) type Failures = HashMap<String, FailureRecord>;
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+// The wait doubles with every failed attempt, up to the policy maximum
+fn recheck_delay(policy: &FailurePolicy, attempts: u32) -> Duration {
+    let exp = 2_u32.saturating_pow(attempts.saturating_sub(1));
+    policy.base_recheck.saturating_mul(exp).min(policy.max_recheck)
+}
+
+pub(crate) fn should_skip(failures: &Failures, key: &str, now: u64) -> bool {
+    failures.get(key).is_some_and(|record| record.next_check > now)
+}
+
+pub(crate) fn is_quarantined(policy: &FailurePolicy, record: &FailureRecord) -> bool {
+    record.attempts >= policy.quarantine_after
+}
+
+pub(crate) fn record_failure(failures: &mut Failures, policy: &FailurePolicy, key: String, reason: String, now: u64) {
+    let record = failures.entry(key).or_insert_with(|| FailureRecord {
+        reason: String::new(),
+        first_failed: now,
+        last_failed: now,
+        attempts: 0,
+        next_check: now,
+    });
+    record.reason = reason;
+    record.last_failed = now;
+    record.attempts += 1;
+    record.next_check = now + recheck_delay(policy, record.attempts).as_secs();
+}
+
+// Content hashes that have failed often enough to be considered gone, oldest failure first
+pub(crate) fn unavailable<'a>(failures: &'a Failures, policy: &FailurePolicy) -> Vec<(&'a String, &'a FailureRecord)> {
+    let mut unavailable: Vec<_> = failures
+        .iter()
+        .filter(|(_, record)| is_quarantined(policy, record))
+        .collect();
+    unavailable.sort_by_key(|(key, record)| (record.first_failed, *key));
+    unavailable
+}
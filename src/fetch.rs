@@ -0,0 +1,242 @@
+use std::fmt;
+use std::time::SystemTime;
+use bytes::{Bytes, BytesMut};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use reqwest::{header, Client, Response, StatusCode};
+use tokio::time::{sleep, Duration};
+use crate::config::{BodyLimits, RetryPolicy};
+use crate::sniff;
+
+// A fetched body together with the content type the gateway reported and a
+// file name, if the gateway gave us one. truncated is set when we stopped
+// reading before the end of the body, and limit is the byte limit we read it
+// under, so a cached copy can be refetched once the limit is raised.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Fetched {
+    pub(crate) content_type: String,
+    pub(crate) name: Option<String>,
+    #[serde(skip)]
+    pub(crate) body: Bytes,
+    pub(crate) truncated: bool,
+    #[serde(default)]
+    pub(crate) limit: usize,
+}
+
+impl Fetched {
+    // A truncated body read under a smaller limit than the current one
+    pub(crate) fn cut_short(&self, limits: &BodyLimits) -> bool {
+        let mut limit = limits.max_body_bytes;
+        if sniff::is_binary(&sniff::content_type(&self.content_type, self.name.as_deref(), &self.body)) {
+            limit = limit.min(limits.max_binary_bytes);
+        }
+        self.truncated && limit > self.limit
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum FetchError {
+    // The gateway answered with a status we don't accept
+    Status {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    Timeout(reqwest::Error),
+    Connect(reqwest::Error),
+    Request(reqwest::Error),
+    RetriesExhausted {
+        url: String,
+        attempts: usize,
+        last: Box<FetchError>,
+    },
+}
+
+impl FetchError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            FetchError::Status { status, .. } => matches!(
+                *status,
+                StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            FetchError::Timeout(_) | FetchError::Connect(_) => true,
+            FetchError::Request(_) | FetchError::RetriesExhausted { .. } => false,
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            FetchError::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Status { status, .. } => write!(f, "Unexpected status code: {}", status),
+            FetchError::Timeout(e) => write!(f, "Timed out: {}", e),
+            FetchError::Connect(e) => write!(f, "Connection failed: {}", e),
+            FetchError::Request(e) => write!(f, "Request failed: {}", e),
+            FetchError::RetriesExhausted { url, attempts, last } => {
+                write!(f, "Failed to retrieve {} after {} attempts: {}", url, attempts, last)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            FetchError::Timeout(e)
+        } else if e.is_connect() {
+            FetchError::Connect(e)
+        } else {
+            FetchError::Request(e)
+        }
+    }
+}
+
+pub(crate) fn build_client(policy: &RetryPolicy) -> Client {
+    Client::builder()
+        .timeout(policy.timeout)
+        .build()
+        .expect("Could not build http client")
+}
+
+// Retry-After is either a number of seconds or an http date
+fn parse_retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+}
+
+// Exponential backoff with "equal jitter": half the delay is fixed, the other half random
+fn get_delay(policy: &RetryPolicy, attempt: usize) -> Duration {
+    let exp = policy.base_delay.saturating_mul(2_u32.saturating_pow(attempt as u32));
+    let delay = exp.min(policy.max_delay);
+    let half = delay / 2;
+    half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+}
+
+async fn send(client: &Client, url: &str) -> Result<Response, FetchError> {
+    let response = client.get(url).send().await?;
+    match response.status() {
+        StatusCode::OK => Ok(response),
+        status => Err(FetchError::Status {
+            status,
+            retry_after: parse_retry_after(&response),
+        }),
+    }
+}
+
+pub(crate) async fn make_request(client: &Client, policy: &RetryPolicy, url: &str) -> Result<Response, FetchError> {
+    let mut attempt = 0;
+    loop {
+        let error = match send(client, url).await {
+            Ok(response) => {
+                println!("Success! {}", url);
+                return Ok(response);
+            }
+            Err(e) => e,
+        };
+
+        if !error.is_retryable() {
+            return Err(error);
+        }
+        attempt += 1;
+        if attempt > policy.max_retries {
+            return Err(FetchError::RetriesExhausted {
+                url: url.to_string(),
+                attempts: attempt,
+                last: Box::new(error),
+            });
+        }
+
+        // A server supplied Retry-After wins over our own schedule, but is capped
+        // so a misbehaving gateway can't park a worker indefinitely
+        let delay = error
+            .retry_after()
+            .map(|d| d.min(policy.max_delay))
+            .unwrap_or_else(|| get_delay(policy, attempt - 1));
+        println!("{} ({}). Retrying in {:.1} seconds", error, url, delay.as_secs_f64());
+        sleep(delay).await;
+    }
+}
+
+// Streams the body, keeping at most limits.max_body_bytes of it. Once enough
+// has arrived to sniff, binary payloads are cut off at limits.max_binary_bytes
+// so a video doesn't get downloaded just to be thrown away.
+pub(crate) async fn fetch_document(client: &Client, policy: &RetryPolicy, limits: &BodyLimits, url: &str) -> Result<Fetched, FetchError> {
+    let mut response = make_request(client, policy, url).await?;
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_owned();
+    let name = file_name(&response);
+
+    let mut limit = limits.max_body_bytes;
+    if sniff::is_binary(&sniff::essence(&content_type)) {
+        limit = limit.min(limits.max_binary_bytes);
+    }
+    if let Some(length) = response.content_length() {
+        if length > limit as u64 {
+            println!("{} is {} bytes, only reading the first {}", url, length, limit);
+        }
+    }
+
+    let mut body = BytesMut::new();
+    let mut sniffed = false;
+    let mut truncated = false;
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if !sniffed && body.len() >= sniff::SNIFF_LEN {
+            sniffed = true;
+            if sniff::is_binary(&sniff::content_type(&content_type, name.as_deref(), &body)) {
+                limit = limit.min(limits.max_binary_bytes);
+            }
+        }
+        // A body of exactly limit bytes is complete, so only stop once more
+        // than that has arrived
+        if body.len() > limit {
+            body.truncate(limit);
+            truncated = true;
+            break;
+        }
+    }
+
+    Ok(Fetched { content_type, name, body: body.freeze(), truncated, limit })
+}
+
+// From Content-Disposition, or the last segment of the (possibly redirected) url
+fn file_name(response: &Response) -> Option<String> {
+    let disposition = response
+        .headers()
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .split(';')
+                .filter_map(|param| param.trim().split_once('='))
+                .find(|(key, _)| key.eq_ignore_ascii_case("filename"))
+                .map(|(_, name)| name.trim_matches('"').to_string())
+        });
+    disposition.or_else(|| {
+        response
+            .url()
+            .path_segments()?
+            .rfind(|segment| !segment.is_empty())
+            .filter(|segment| segment.contains('.'))
+            .map(String::from)
+    })
+}
@@ -1,206 +1,560 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use anyhow::{anyhow, Context};
 use serde::{Serialize, Deserialize};
-use scraper::{Html, Selector};
-use reqwest::{header, Error, Response};
+use crate::analyze::Analyzer;
 use crate::disk;
-use crate::chain;
+use crate::entity::{self, Entity};
+use crate::chain::{self, ContentHash, Names};
+use crate::config::{Concurrency, Config};
+use crate::extract::{self, Extracted, Registry};
+use crate::failures::{self, Failures};
+use crate::fetch::{self, FetchError, Fetched};
+use crate::language;
+use crate::postings::{DocId, DocTerms, Index};
+use crate::store::{AltTexts, Store};
+use crate::render::{self, Renderer};
+use crate::sniff;
+use reqwest::Client;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 use tokio::task;
-use anyhow::anyhow;
 use futures::future::try_join_all;
-use tokio::sync::Semaphore;
-use tokio::time::{sleep, Duration};
-use futures::executor::block_on;
-
-
-#[derive(Serialize, Deserialize, Debug, Eq, Hash, PartialEq, Clone)]
-pub(crate) struct WordLocation {
-    ens_name: String,
-    pub(crate) ipfs_hash: String,
-    pub(crate) location: usize
-}
-
-// index = string -> [word_locations]
-pub(crate) type Index = HashMap<String, HashSet<WordLocation>>;
-// docs = ipfs_hash -> [words_in_doc]
-pub(crate) type Docs = HashMap<String, Vec<String>>;
-
-const MAX_CONCURRENT_REQUESTS: usize = 10;
-const MAX_RETRIES: usize = 5;
-
-fn get_delay(i: usize) -> Duration {
-    let sleep_duration = (1 + 2_i32.pow(i as u32));
-    Duration::from_secs(sleep_duration as u64)
-}
-
-async fn make_request(url: &str) -> Result<Response, anyhow::Error> {
-    for i in 0..MAX_RETRIES {
-        let response = reqwest::get(url).await?;
-        let result = match response.status() {
-            reqwest::StatusCode::OK => Ok(response),
-            reqwest::StatusCode::BAD_REQUEST => Err(anyhow!("Bad Request")),
-            reqwest::StatusCode::UNAUTHORIZED => Err(anyhow!("Unauthorized")),
-            reqwest::StatusCode::FORBIDDEN => Err(anyhow!("Forbidden")),
-            reqwest::StatusCode::NOT_FOUND => Err(anyhow!("Not Found")),
-            reqwest::StatusCode::INTERNAL_SERVER_ERROR => Err(anyhow!("Internal Server Error")),
-            reqwest::StatusCode::TOO_MANY_REQUESTS => Err(anyhow!("Too Many Requests")),
-            status => Err(anyhow!("Unexpected status code: {}", status))
-        };
-
-        if let Err(e) = &result {
-            let error_message = e.to_string();
-            if error_message == "Too Many Requests" {
-                let delay = get_delay(i);
-                println!("Rate limited. Retrying in {} seconds", delay.as_secs());
-                sleep(delay).await;
-                continue;
-            } else {
-                println!("Error: {}", error_message);
-            }
-        } else {
-            println!("Success! {}", url);
+use tokio::sync::{mpsc, Semaphore};
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct Document {
+    // title, description etc, keyed by field name
+    pub(crate) fields: BTreeMap<String, String>,
+    // the body text, one extracted fragment per line. analyze::spans gives
+    // where each body position is in it.
+    pub(crate) text: String,
+    // ISO 639-1 code, if it could be detected
+    #[serde(default)]
+    pub(crate) language: Option<String>,
+}
+
+// docs = ipfs_hash -> document
+pub(crate) type Docs = HashMap<String, Document>;
+
+// What parsing one response produces. Alt texts are for other documents, so
+// they can only be applied once everything has been merged.
+struct Parsed {
+    ipfs_hash: String,
+    // None if there was no extractor for it
+    document: Option<(DocTerms, Document)>,
+    // (referenced CID, alt text)
+    alt_texts: Vec<(String, String)>,
+}
+
+// CPU bound, so this runs on the blocking pool rather than an async worker
+fn process_response(extractors: &Registry, analyzer: &Analyzer, fetched: &Fetched, ipfs_hash: String) -> Parsed {
+    let mut parsed = Parsed { ipfs_hash: ipfs_hash.clone(), document: None, alt_texts: Vec::new() };
+    // Gateways often send octet-stream or nothing at all, so look at the body too
+    let content_type = sniff::content_type(&fetched.content_type, fetched.name.as_deref(), &fetched.body);
+    if content_type != sniff::essence(&fetched.content_type) {
+        println!("Treating {} as {} (served as '{}')", ipfs_hash, content_type, fetched.content_type);
+    }
+    if fetched.truncated {
+        println!("Only indexing the first {} bytes of {}", fetched.body.len(), ipfs_hash);
+    }
+
+    let input = extract::Input {
+        content_type: &content_type,
+        header: &fetched.content_type,
+        body: &fetched.body,
+    };
+    match extractors.extract(&input) {
+        Some(mut extracted) => {
+            parsed.alt_texts.append(&mut extracted.alt_texts);
+            parsed.document = Some(index_extracted(analyzer, extracted));
         }
+        None => println!("No extractor for {}. Content type: {}", ipfs_hash, content_type),
+    }
 
-        return result;
+    parsed
+}
+
+fn merge(index: &mut Index, docs: &mut Docs, ipfs_hash: String, terms: DocTerms, document: Document) {
+    index.add(ipfs_hash.clone(), terms);
+    docs.insert(ipfs_hash, document);
+}
+
+// Index what referencing pages say about a document in its ALT_TEXT field,
+// for the documents in the index
+fn apply_alt_texts(analyzer: &Analyzer, index: &mut Index, docs: &mut Docs, alt_texts: &AltTexts) {
+    let by_cid: HashMap<String, DocId> = index
+        .doc_hashes()
+        .filter_map(|(doc, hash)| Some((chain::normalize_cid(hash)?, doc)))
+        .collect();
+    let mut found: BTreeMap<DocId, Vec<String>> = BTreeMap::new();
+    for (cid, alt) in alt_texts.values().flatten() {
+        let Some(doc) = chain::normalize_cid(cid).and_then(|cid| by_cid.get(&cid)) else { continue };
+        let alts = found.entry(*doc).or_default();
+        if !alts.contains(alt) {
+            alts.push(alt.clone());
+        }
+    }
+    let mut by_term: HashMap<String, Vec<(DocId, String, Vec<u32>)>> = HashMap::new();
+    for (doc_id, alts) in found {
+        let text = alts.join(" ");
+        let Some(doc) = docs.get_mut(index.doc_hash(doc_id)) else { continue };
+        let language = doc.language.as_deref().unwrap_or(&analyzer.language);
+        let mut terms = DocTerms::new();
+        process_text(analyzer, language, &text, extract::ALT_TEXT, &mut terms);
+        doc.fields.insert(extract::ALT_TEXT.to_string(), text);
+        for (term, fields) in terms {
+            let postings = by_term.entry(term).or_default();
+            postings.extend(fields.into_iter().map(|(field, positions)| (doc_id, field, positions)));
+        }
+    }
+    for (term, postings) in by_term {
+        index.insert(term, postings);
     }
+}
 
-    return Err(anyhow!("Failed to retrieve {} after {} retries", url, MAX_RETRIES));
+// The language of the body, or of the title and description for documents
+// with little or no body, like media and json metadata
+fn detect_language(extracted: &Extracted, text: &str) -> Option<&'static str> {
+    language::detect(text).or_else(|| {
+        let fields: Vec<&str> = [extract::TITLE, extract::DESCRIPTION, extract::OG_DESCRIPTION]
+            .iter()
+            .filter_map(|field| extracted.fields.get(*field).map(String::as_str))
+            .collect();
+        language::detect(&fields.join(" "))
+    })
 }
 
-async fn process_response(response: Response, ipfs_hash: String) -> Result<(Index, Docs), Error> {
-    let mut index = Index::new();
-    let mut docs = Docs::new();
-    let content_type = response
-        .headers()
-        .get(header::CONTENT_TYPE)
-        .and_then(|value| value.to_str().ok())
-        .unwrap_or("")
-        .to_owned();  // Clone the string here
+// The terms of every searchable field and the body, and the document to keep
+// for context. Everything is stemmed for the document's language.
+fn index_extracted(analyzer: &Analyzer, extracted: Extracted) -> (DocTerms, Document) {
+    let mut terms = DocTerms::new();
+    let text = extracted.text.join("\n");
+    let detected = detect_language(&extracted, &text);
+    let language = detected.unwrap_or(&analyzer.language);
+    for (field, value) in &extracted.fields {
+        if extract::is_indexed(field) {
+            process_text(analyzer, language, value, field, &mut terms);
+        }
+    }
+    process_text(analyzer, language, &text, extract::BODY, &mut terms);
 
-    let body = response.text().await?;
+    let mut fields = extracted.fields;
+    let searchable = fields.iter().filter(|(field, _)| extract::is_indexed(field)).map(|(_, value)| value.as_str());
+    let entities: Vec<_> = searchable.chain([text.as_str()]).flat_map(entity::find).collect();
+    index_entities(entities, &mut terms, &mut fields);
+    let document = Document {
+        fields,
+        text,
+        language: detected.map(String::from),
+    };
+    (terms, document)
+}
 
-    match content_type {
-        // Check if the Content-Type is HTML
-        ct if ct.contains("text/html") => {
-            let document = Html::parse_document(&body);
-            // todo: include head + body?
-            let selector = Selector::parse("body").unwrap();
-            let elements = document.select(&selector);
-            for element in elements {
-                let words = element.text().map(|s| s.to_owned()).collect::<Vec<_>>();
+// Entities skip the analyzer, their terms are already normalized. Each kind
+// is indexed and stored in its own field, once per document. Extractors can
+// set a field of the same name, so Index::add sorts the positions.
+fn index_entities(entities: Vec<Entity>, terms: &mut DocTerms, fields: &mut BTreeMap<String, String>) {
+    let mut by_field: BTreeMap<&str, Vec<Entity>> = BTreeMap::new();
+    for entity in entities {
+        let found = by_field.entry(entity.field).or_default();
+        if !found.iter().any(|other| other.term == entity.term) {
+            found.push(entity);
+        }
+    }
+    for (field, entities) in by_field {
+        for (i, entity) in entities.iter().enumerate() {
+            terms.entry(entity.term.clone())
+                .or_default()
+                .entry(field.to_string())
+                .or_default()
+                .push(i as u32);
+        }
+        let display: Vec<&str> = entities.iter().map(|entity| entity.display.as_str()).collect();
+        // an extractor can have set a field of the same name already
+        fields.entry(field.to_string())
+            .and_modify(|value| {
+                value.push(' ');
+                value.push_str(&display.join(" "));
+            })
+            .or_insert_with(|| display.join(" "));
+    }
+}
 
-//                let words = element.text().collect::<Vec<_>>();
-                let text = words.join(" ");
-                docs.insert(ipfs_hash.clone(), words);
-                process_text(&text, ipfs_hash.clone(), &mut index);
-            }
-        },
-        // Check if the Content-Type is plain text
-        ct if ct.contains("text/plain") || ct.contains("application/json") => {
-            process_text(&body, ipfs_hash.clone(), &mut index);
-        },
-        // Handle other content types, including binary
-        _ => {
-            println!("The document is not HTML or plain text or json. Content type: {}", content_type);
-            // Here, you might want to log this event, or handle binary data if needed.
-        },
-    }
-
-    Ok((index, docs))
-}
-
-fn process_text(text: &str, ipfs: String, index: &mut Index) {
-    text.split_whitespace()
-        .enumerate()
-        .for_each(|(i, word)| {
-            let loc = WordLocation {
-                ens_name: String::from("???"),
-                ipfs_hash: ipfs.clone(),
-                location: i,
-            };
-            index.entry(String::from(word))
-                .or_insert_with(HashSet::new)
-                .insert(loc);
+fn process_text(analyzer: &Analyzer, language: &str, text: &str, field: &str, terms: &mut DocTerms) {
+    analyzer.analyze(text, language)
+        .for_each(|(i, term)| {
+            terms.entry(term)
+                .or_default()
+                .entry(field.to_string())
+                .or_default()
+                .push(i as u32);
         });
 }
 
 fn print_index(index: &Index) {
     // Iterate over all values in all vectors associated with every key
-    for (key, values) in index.iter() {
-        println!("Values for key '{}':", key);
-        for value in values {
-            println!("- {} - {}", value.ens_name, value.location);
+    for (term, postings) in index.terms() {
+        println!("Values for key '{}':", term);
+        for posting in postings.iter() {
+            println!("- {} - {} {:?}", index.doc_hash(posting.doc), index.field_name(posting.field), posting.positions);
         }
     }
 }
 
-async fn build_index(index_path: &Path, docs_path: &Path) -> (Index, Docs) {
-    println!("Building index");
-    let index = Arc::new(Mutex::new(Index::new()));
-    let docs = Arc::new(Mutex::new(HashMap::new()));
-    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS));
-
-    let hashes = chain::get_events().await.unwrap();
-    let futures: Vec<_> = hashes.iter().enumerate().map(|(i, hash)| {
-        let semaphore = Arc::clone(&semaphore);
-        let index = Arc::clone(&index);
-        let docs = Arc::clone(&docs);
-        let hash = hash.clone();
-        // let url = format!("https://gateway.pinata.cloud/ipfs/{}", hash);
-        let url = format!("https://ipfs.io/ipfs/{}", hash);
-        println!("Making request to url {}/{} : {}", i + 1, hashes.len(), url);
+const INDEX_DIR: &str = "index";
+const FAILURES_PATH: &str = "failures.json";
+// Every name's contenthash as of the manifest's last block
+const NAMES_FILE: &str = "names.csv";
+const UNAVAILABLE_REPORT_PATH: &str = "unavailable.csv";
+
+// What happened to a content hash during a build
+enum Outcome {
+    // failed recently and isn't due for a recheck yet
+    Skipped,
+    Fetched,
+    Failed(String),
+}
+
+// One permit pool per gateway, so a slow gateway can't starve the others
+struct GatewayLimits {
+    ipfs: Semaphore,
+    swarm: Semaphore,
+    arweave: Semaphore,
+}
+
+impl GatewayLimits {
+    fn new(concurrency: &Concurrency) -> Self {
+        GatewayLimits {
+            ipfs: Semaphore::new(concurrency.ipfs),
+            swarm: Semaphore::new(concurrency.swarm),
+            arweave: Semaphore::new(concurrency.arweave),
+        }
+    }
+
+    fn for_hash(&self, hash: &ContentHash) -> &Semaphore {
+        match hash {
+            ContentHash::Ipfs(_) => &self.ipfs,
+            ContentHash::Swarm(_) => &self.swarm,
+            ContentHash::Arweave(_) => &self.arweave,
+        }
+    }
+}
+
+// Serve from the content cache when we can, otherwise fetch through the
+// gateway, render it if it's an app shell and cache the result for the next
+// build. The caller holds the gateway permit, which also covers the renderer's
+// fetches.
+async fn fetch_or_load(
+    client: &Client,
+    config: &Config,
+    renderer: Option<&dyn Renderer>,
+    render_limit: &Semaphore,
+    content_hash: &ContentHash,
+    progress: &str,
+) -> Result<Fetched, FetchError> {
+    let key = content_hash.to_string();
+    let cache_dir = config.cache_dir.clone();
+    let load_key = key.clone();
+    match task::spawn_blocking(move || disk::load_cached(&cache_dir, &load_key)).await {
+        Ok(Ok(Some(fetched))) if fetched.cut_short(&config.body_limits) => {
+            println!("Refetching {}, the cached copy was cut off at {} bytes", key, fetched.limit);
+        }
+        Ok(Ok(Some(fetched))) => {
+            println!("Loaded {} from cache {}", key, progress);
+            return Ok(fetched);
+        }
+        Ok(Ok(None)) => {}
+        Ok(Err(e)) => println!("Could not read {} from cache: {}", key, e),
+        Err(e) => println!("Could not read {} from cache: {}", key, e),
+    }
+
+    let url = config.gateways.url(content_hash);
+    println!("Making request to url {} : {}", progress, url);
+    let fetched = fetch::fetch_document(client, &config.retry, &config.body_limits, &url).await?;
+    let fetched = match renderer {
+        Some(renderer) => render::render_if_needed(renderer, render_limit, &url, fetched).await,
+        None => fetched,
+    };
+
+    let cache_dir = config.cache_dir.clone();
+    let cached = fetched.clone();
+    let saved = task::spawn_blocking(move || disk::save_cached(&cache_dir, &key, &cached)).await;
+    if let Ok(Err(e)) = saved {
+        println!("Could not cache {}: {}", content_hash, e);
+    }
+    Ok(fetched)
+}
+
+// Update the negative cache with this build's results and report the sites
+// that look permanently gone
+fn record_outcomes(config: &Config, previous: &Failures, outcomes: Vec<(String, Outcome)>) {
+    let mut failures = previous.clone();
+    let now = failures::now();
+    let mut skipped = 0;
+    for (hash, outcome) in outcomes {
+        match outcome {
+            Outcome::Skipped => skipped += 1,
+            Outcome::Fetched => {
+                failures.remove(&hash);
+            }
+            Outcome::Failed(reason) => failures::record_failure(&mut failures, &config.failures, hash, reason, now),
+        }
+    }
+
+    let unavailable = failures::unavailable(&failures, &config.failures);
+    println!("Skipped {} content hashes that failed recently, {} are unavailable (see {})",
+             skipped, unavailable.len(), UNAVAILABLE_REPORT_PATH);
+    if let Err(e) = disk::save_unavailable_report(Path::new(UNAVAILABLE_REPORT_PATH), &unavailable) {
+        println!("Could not save unavailable report: {}", e);
+    }
+    if let Err(e) = disk::save_failures(Path::new(FAILURES_PATH), &failures) {
+        println!("Could not save failures: {}", e);
+    }
+}
+
+// What every fetch task shares
+struct Pipeline {
+    config: Config,
+    extractors: Arc<Registry>,
+    client: Client,
+    renderer: Option<Arc<dyn Renderer>>,
+    limits: GatewayLimits,
+    parse_limit: Semaphore,
+    render_limit: Semaphore,
+    failures: Failures,
+    started: u64,
+}
+
+// fetch -> parse -> merge. Fetches are bounded per gateway, parsing is bounded
+// by the parse permits and runs on the blocking pool, and a single task owns
+// the index and merges results as they arrive over a bounded channel. Returns
+// the alt texts found on each page, for applying once everything is in.
+async fn run_pipeline(
+    pipeline: &Arc<Pipeline>,
+    hashes: Vec<ContentHash>,
+    mut index: Index,
+    mut docs: Docs,
+) -> (Index, Docs, AltTexts, Vec<(String, Outcome)>) {
+    let (sender, mut receiver) = mpsc::channel::<Parsed>(pipeline.config.concurrency.parse);
+    let merger = task::spawn(async move {
+        let mut alt_texts = AltTexts::new();
+        while let Some(parsed) = receiver.recv().await {
+            if !parsed.alt_texts.is_empty() {
+                alt_texts.insert(parsed.ipfs_hash.clone(), parsed.alt_texts);
+            }
+            if let Some((terms, document)) = parsed.document {
+                merge(&mut index, &mut docs, parsed.ipfs_hash, terms, document);
+            }
+        }
+        (index, docs, alt_texts)
+    });
+
+    let total = hashes.len();
+    let futures: Vec<_> = hashes.into_iter().enumerate().map(|(i, content_hash)| {
+        let pipeline = Arc::clone(pipeline);
+        let sender = sender.clone();
         task::spawn(async move {
-            // Acquire a permit from the semaphore
-            let permit = block_on(semaphore.acquire()).unwrap();
-
-            let result = make_request(&url).await;
-            match result {
-                Ok(response) => {
-                    let result = process_response(response, hash).await;
-                    match result {
-                        Ok((local_index, local_docs)) => {
-                            let mut locked_index = index.lock().unwrap();
-                            for (key, value) in local_index {
-                                locked_index.entry(key)
-                                    .and_modify(|e| e.extend(value.clone()))
-                                    .or_insert(value);
-                            }
-                            docs.lock().unwrap().extend(local_docs);
-                        }
-                        Err(e) => {
-                            println!("Error: {}", e);
-                        }
-                    }
-                }
+            let hash = content_hash.to_string();
+            if failures::should_skip(&pipeline.failures, &hash, pipeline.started) {
+                return (hash, Outcome::Skipped);
+            }
+            let progress = format!("{}/{}", i + 1, total);
+            // Held until the document is handed to a parser, so fetched bodies
+            // can't pile up waiting for a parse permit
+            let gateway_permit = pipeline.limits.for_hash(&content_hash).acquire().await.unwrap();
+            let renderer = pipeline.renderer.as_deref();
+            let result = fetch_or_load(&pipeline.client, &pipeline.config, renderer, &pipeline.render_limit, &content_hash, &progress).await;
+            let fetched = match result {
+                Ok(fetched) => fetched,
                 Err(e) => {
                     println!("Error: {}", e);
+                    return (hash, Outcome::Failed(e.to_string()));
+                }
+            };
+
+            // Held until the merger accepts the result, so parsed documents can't pile up
+            let _permit = pipeline.parse_limit.acquire().await.unwrap();
+            drop(gateway_permit);
+            let doc_hash = hash.clone();
+            let parser = Arc::clone(&pipeline);
+            let parsed = task::spawn_blocking(move || {
+                process_response(&parser.extractors, &parser.config.analyzer, &fetched, doc_hash)
+            });
+            match parsed.await {
+                Ok(parsed) => {
+                    // Only fails if the merger is gone, in which case there's nothing to do
+                    let _ = sender.send(parsed).await;
                 }
+                Err(e) => println!("Error: {}", e),
             }
-            drop(permit);
+            (hash, Outcome::Fetched)
         })
     }).collect();
+    drop(sender);
 
-    try_join_all(futures).await.expect("Could not join futures together");
+    let outcomes = try_join_all(futures).await.expect("Could not join futures together");
+    let (index, docs, alt_texts) = merger.await.expect("Could not merge index");
+    (index, docs, alt_texts, outcomes)
+}
+
+// Only content hashes that aren't in the store yet are indexed, into a new
+// segment. Documents whose content hash isn't set on any name anymore are
+// deleted. Every page's alt texts are kept in the store, and the documents
+// this update indexes get those about them. Documents from earlier updates
+// that new alt texts refer to are indexed again from the content cache and
+// replace their old copy. Searches go on meanwhile, the store is only locked
+// while it's read or a change is committed.
+pub async fn update_index(config: &Config, extractors: Arc<Registry>, store: &RwLock<Store>) -> Result<(), anyhow::Error> {
+    println!("Building index");
+    let client = fetch::build_client(&config.retry);
+    let renderer = render::from_config(config, &client);
+    let failures = disk::load_failures(Path::new(FAILURES_PATH)).unwrap_or_else(|e| {
+        println!("Could not load failures, rechecking everything: {}", e);
+        Failures::new()
+    });
+    let pipeline = Arc::new(Pipeline {
+        config: config.clone(),
+        extractors,
+        client,
+        renderer,
+        limits: GatewayLimits::new(&config.concurrency),
+        parse_limit: Semaphore::new(config.concurrency.parse),
+        render_limit: Semaphore::new(config.concurrency.render),
+        failures,
+        started: failures::now(),
+    });
+
+    // Only the logs since the last update are read, unless there are no names
+    // saved to apply them to
+    let names_path = Path::new(INDEX_DIR).join(NAMES_FILE);
+    let (mut names, last_block) = match disk::load_names(&names_path) {
+        Ok(Some(names)) => (names, store.read().unwrap().last_block()),
+        Ok(None) => (Names::new(), None),
+        Err(e) => {
+            println!("Could not load names, reading every event again: {}", e);
+            (Names::new(), None)
+        }
+    };
+    let last_block = chain::get_events(&mut names, last_block)
+        .await
+        .map_err(|e| anyhow!("Could not get contenthash changes: {}", e))?;
+    disk::save_names(&names_path, &names).context("Could not save names")?;
+    let hashes: HashSet<ContentHash> = names.into_values().collect();
+    let current: HashSet<String> = hashes.iter().map(ContentHash::to_string).collect();
+    let hashes: Vec<ContentHash> = {
+        let mut store = store.write().unwrap();
+        let stale: Vec<String> = store.hashes().filter(|hash| !current.contains(*hash)).cloned().collect();
+        store.delete(&stale).context("Could not delete documents from index")?;
+        let hashes: Vec<ContentHash> = hashes.into_iter().filter(|hash| !store.contains(&hash.to_string())).collect();
+        println!("Indexing {} new content hashes, deleted {} that are no longer set", hashes.len(), stale.len());
+        hashes
+    };
+    let (mut index, mut docs, mut alt_texts, mut outcomes) = run_pipeline(&pipeline, hashes, Index::new(), Docs::new()).await;
+
+    let targets = referenced(&store.read().unwrap(), &index, &alt_texts);
+    if !targets.is_empty() {
+        println!("Indexing {} documents again for their new alt texts", targets.len());
+        let (more_index, more_docs, more_alt_texts, more) = run_pipeline(&pipeline, targets, index, docs).await;
+        (index, docs) = (more_index, more_docs);
+        alt_texts.extend(more_alt_texts);
+        outcomes.extend(more);
+    }
+    // saved with the segment
+    {
+        let mut store = store.write().unwrap();
+        for (page, page_alt_texts) in alt_texts {
+            store.set_alt_texts(page, page_alt_texts);
+        }
+    }
+    apply_alt_texts(&config.analyzer, &mut index, &mut docs, store.read().unwrap().alt_texts());
+    record_outcomes(config, &pipeline.failures, outcomes);
 
-    print_index(&index.lock().unwrap());
-    disk::save_index(index_path, &index.lock().unwrap()).expect("Could not save index to file");
-    disk::save_docs(docs_path, &docs.lock().unwrap()).expect("Could not save docs to file");
-    let index = index.lock().unwrap().clone();
-    let docs = docs.lock().unwrap().clone();
+    print_index(&index);
+    if !index.is_empty() {
+        let segment = store.write().unwrap().new_segment();
+        let written = segment.write(&index, &docs).context("Could not save index segment")?;
+        store.write().unwrap().add_segment(written).context("Could not save index manifest")?;
+    }
+    store.write().unwrap().set_last_block(last_block).context("Could not save index manifest")?;
+    Ok(())
+}
 
-    return (index, docs);
+// Live documents from earlier updates that the alt texts are about
+fn referenced(store: &Store, index: &Index, alt_texts: &AltTexts) -> Vec<ContentHash> {
+    if alt_texts.is_empty() {
+        return Vec::new();
+    }
+    let cids: HashSet<String> = alt_texts.values().flatten().filter_map(|(cid, _)| chain::normalize_cid(cid)).collect();
+    let indexed: HashSet<&String> = index.doc_hashes().map(|(_, hash)| hash).collect();
+    store
+        .hashes()
+        .filter(|hash| !indexed.contains(hash))
+        .filter(|hash| chain::normalize_cid(hash).is_some_and(|cid| cids.contains(&cid)))
+        .filter_map(|hash| hash.parse().ok())
+        .collect()
 }
 
-pub async fn load_index(force_rebuild: bool) -> (Index, Docs) {
-    // Save index to disk
-    let index_path = Path::new("index.json");
-    let docs_path = Path::new("docs.json");
-    if index_path.exists() && docs_path.exists() && !force_rebuild {
-        let index = disk::load_index(index_path).expect("Could not load index from file");
-        let docs = disk::load_docs(docs_path).expect("Could not load docs from file");
-        return (index, docs);
-    } else {
-        return build_index(index_path, docs_path).await;
+// Opens the index for searching, cleared to be built again from scratch if
+// rebuild is set or it was built with another analyzer
+pub fn open_index(config: &Config, mut rebuild: bool) -> Result<Store, anyhow::Error> {
+    let mut store = Store::open(Path::new(INDEX_DIR)).context("Could not open index")?;
+    let fingerprint = config.analyzer.fingerprint();
+    if store.segment_count() > 0 && store.analyzer() != fingerprint {
+        println!("The index was built with analyzer {} but it's now {}, rebuilding it", store.analyzer(), fingerprint);
+        rebuild = true;
     }
-}
\ No newline at end of file
+    if rebuild {
+        store.clear().context("Could not clear index")?;
+    }
+    store.set_analyzer(fingerprint);
+    Ok(store)
+}
+
+// Reads every index file to check it against its checksum, without changing
+// any of them
+pub fn verify_index() -> Result<(), anyhow::Error> {
+    let store = Store::open_read_only(Path::new(INDEX_DIR)).context("Could not open index")?;
+    store.verify().context("Index is corrupt")?;
+    println!("Checked {} segments with {} documents", store.segment_count(), store.doc_count());
+    Ok(())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::Filter;
+
+    const IMAGE_V0: &str = "QmRAQB6YaCyidP37UdDnjFY5vQuiBrcqdyoW1CuDgwxkD4";
+    const IMAGE_V1: &str = "bafybeibj6lixxzqtsb45ysdjnupvqkufgdvzqbnvmhw2kf7cfkesy7r7d4";
+
+    fn single(hash: &str) -> (Index, Docs) {
+        let mut index = Index::new();
+        index.add(hash.to_string(), DocTerms::new());
+        let mut docs = Docs::new();
+        docs.insert(hash.to_string(), Document::default());
+        (index, docs)
+    }
+
+    #[test]
+    fn alt_texts_from_earlier_updates_reach_documents_indexed_later() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+        // the page is indexed first, its img points at an image nothing has indexed yet
+        let (index, docs) = single("QmPage");
+        store.set_alt_texts("QmPage".to_string(), vec![(IMAGE_V1.to_string(), "A red fox".to_string())]);
+        let written = store.new_segment().write(&index, &docs).unwrap();
+        store.add_segment(written).unwrap();
+        drop(store);
+
+        // a later update indexes the image, by its v0 CID
+        let store = Store::open(dir.path()).unwrap();
+        let (mut index, mut docs) = single(IMAGE_V0);
+        let analyzer = Analyzer::new(vec![Filter::CaseFold], "en".to_string());
+        apply_alt_texts(&analyzer, &mut index, &mut docs, store.alt_texts());
+        assert_eq!(docs[IMAGE_V0].fields[extract::ALT_TEXT], "A red fox");
+        let (_, fox) = index.terms().find(|(term, _)| *term == "fox").unwrap();
+        let postings: Vec<_> = fox.iter().collect();
+        assert_eq!(index.doc_hash(postings[0].doc), IMAGE_V0);
+        assert_eq!(index.field_name(postings[0].field), extract::ALT_TEXT);
+    }
+}
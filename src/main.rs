@@ -1,21 +1,34 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::io::{stdin, stdout, Write};
-use tokio;
-use ethers::prelude::*;
-use regex::Regex;
+use std::process;
+use std::sync::{Arc, RwLock};
 use serde::{Serialize, Deserialize};
-use tokio::io::split;
-use warp::{http::Response, Filter};
-use crate::index::Docs;
+use crate::analyze::Analyzer;
+use crate::config::Config;
+use crate::index::Document;
+use crate::postings::{DocId, FieldId};
+use crate::segment::Segment;
+use crate::store::Store;
 
 mod index;
+mod analyze;
 mod chain;
+mod charset;
+mod config;
 mod disk;
+mod entity;
+mod extract;
+mod failures;
+mod fetch;
+mod language;
+mod postings;
+mod render;
+mod segment;
+mod store;
+mod sniff;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 enum QueryToken {
-    Word(String),
-    Phrase(String),
     And,
     Or,
 }
@@ -24,50 +37,201 @@ struct Query {
     subquery1: Option<Box<Query>>,
     subquery2: Option<Box<Query>>,
     connector: Option<QueryToken>,
-    base: Option<String>
+    base: Option<String>,
+    // only match base in this field, from field:word
+    field: Option<String>,
+    // terms that have to follow each other in one field, with their position
+    // relative to the first, from a quoted query
+    phrase: Option<Vec<(u32, String)>>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 struct QueryResponse {
     ens_name: String,
     ipfs_hash: String,
-    context: String
+    title: Option<String>,
+    context: String,
+    // char offsets of the matched word in context
+    highlight: Option<(usize, usize)>,
+    score: f32,
+    language: Option<String>,
+}
+
+// A document matching (part of) a query, with a body position to show context around
+#[derive(Clone, Copy)]
+struct Hit {
+    score: f32,
+    location: Option<usize>,
+}
+
+impl Hit {
+    fn combine(self, other: Hit) -> Hit {
+        Hit {
+            score: self.score + other.score,
+            location: self.location.or(other.location),
+        }
+    }
 }
 
 const CONTEXT_WINDOW: usize = 5;
-const MAX_RESULTS: i32 = 20;
+const MAX_RESULTS: usize = 20;
+// Score multiplier for documents in the searcher's language
+const LANGUAGE_BOOST: f32 = 1.5;
 
-fn retrieve_from_index(query: Query, index: &index::Index, docs: &Docs) -> Vec<QueryResponse> {
-    let mut results: Vec<QueryResponse> = Vec::new();
+// Every matching word adds its field's boost to the document's score, so a
+// word in the title counts for more than one in the body
+fn score_query(query: &Query, index: &Segment) -> HashMap<DocId, Hit> {
+    if let Some(phrase) = &query.phrase {
+        return score_phrase(phrase, index);
+    }
+    if let Some(base) = &query.base {
+        let mut hits: HashMap<DocId, Hit> = HashMap::new();
+        let postings = index.postings(base).into_iter().flatten();
+        for posting in postings {
+            let field = index.field_name(posting.field);
+            if query.field.as_ref().is_some_and(|wanted| wanted != field) {
+                continue;
+            }
+            // positions are sorted, so the first is the earliest. Only a
+            // corrupt segment has a posting without any.
+            let Some(&first) = posting.positions.first() else { continue };
+            let hit = hits.entry(posting.doc).or_insert(Hit { score: 0.0, location: None });
+            hit.score += extract::field_boost(field) * posting.positions.len() as f32;
+            if field == extract::BODY {
+                let first = first as usize;
+                hit.location = Some(hit.location.map_or(first, |l| l.min(first)));
+            }
+        }
+        return hits;
+    }
 
-    if let Some(base) = query.base {
-        let locs = index.get(&base).unwrap();
-        for loc in locs {
-            let doc = docs.get(&loc.ipfs_hash).unwrap();
-            let doc_slice = &doc[loc.location-CONTEXT_WINDOW..loc.location+CONTEXT_WINDOW];
-            results.push(QueryResponse {
-                ens_name: "???".to_string(),
-                ipfs_hash: loc.ipfs_hash.clone(),
-                context: doc_slice.join(" "),
-            })
+    let left = query.subquery1.as_ref().map(|q| score_query(q, index)).unwrap_or_default();
+    let mut right = query.subquery2.as_ref().map(|q| score_query(q, index)).unwrap_or_default();
+    // A side that was nothing but stopwords shouldn't empty an AND
+    let is_blank = |q: &Option<Box<Query>>| q.as_ref().is_some_and(|q| q.base.as_deref() == Some(""));
+    if query.connector == Some(QueryToken::And) && is_blank(&query.subquery1) {
+        return right;
+    }
+    if query.connector == Some(QueryToken::And) && is_blank(&query.subquery2) {
+        return left;
+    }
+    if query.connector == Some(QueryToken::And) {
+        left.into_iter()
+            .filter_map(|(hash, hit)| right.remove(&hash).map(|other| (hash, hit.combine(other))))
+            .collect()
+    } else {
+        for (hash, hit) in left {
+            right.entry(hash)
+                .and_modify(|other| *other = hit.combine(*other))
+                .or_insert(hit);
+        }
+        right
+    }
+}
+
+// Documents with every term of the phrase at its offset from the first, in
+// the same field. Each occurrence counts once per term, like the terms would
+// on their own.
+fn score_phrase(phrase: &[(u32, String)], index: &Segment) -> HashMap<DocId, Hit> {
+    // where the phrase could start in each (document, field), given the terms so far
+    let mut starts: HashMap<(DocId, FieldId), Vec<u32>> = HashMap::new();
+    for (i, (offset, term)) in phrase.iter().enumerate() {
+        let mut found = HashMap::new();
+        for posting in index.postings(term).into_iter().flatten() {
+            let key = (posting.doc, posting.field);
+            // positions are sorted, so these are too
+            let term_starts = posting.positions.iter().filter_map(|position| position.checked_sub(*offset));
+            let kept: Vec<u32> = match starts.get(&key) {
+                _ if i == 0 => term_starts.collect(),
+                Some(before) => term_starts.filter(|start| before.binary_search(start).is_ok()).collect(),
+                None => continue,
+            };
+            if !kept.is_empty() {
+                found.insert(key, kept);
+            }
         }
-    } else if query.connector == Some(QueryToken::Or) {
-        let left = retrieve_from_index(*query.subquery1.unwrap(), index, docs);
-        let right = retrieve_from_index(*query.subquery2.unwrap(), index, docs);
-        let left_set: HashSet<_> = left.into_iter().collect();
-        let right_set: HashSet<_> = right.into_iter().collect();
+        starts = found;
+    }
 
-        return left_set.union(&right_set).cloned().collect();
-    } else if query.connector == Some(QueryToken::And) {
-        let left = retrieve_from_index(*query.subquery1.unwrap(), index, docs);
-        let right = retrieve_from_index(*query.subquery2.unwrap(), index, docs);
-        let left_set: HashSet<_> = left.into_iter().collect();
-        let right_set: HashSet<_> = right.into_iter().collect();
+    let mut hits: HashMap<DocId, Hit> = HashMap::new();
+    for ((doc, field), starts) in starts {
+        let field = index.field_name(field);
+        let hit = hits.entry(doc).or_insert(Hit { score: 0.0, location: None });
+        hit.score += extract::field_boost(field) * (starts.len() * phrase.len()) as f32;
+        if field == extract::BODY {
+            let first = starts[0] as usize;
+            hit.location = Some(hit.location.map_or(first, |l| l.min(first)));
+        }
+    }
+    hits
+}
 
-        return left_set.intersection(&right_set).cloned().collect();
+// CONTEXT_WINDOW words either side of the matched body word, cut from the
+// original text, and where the match is within it
+fn context(doc: &Document, location: Option<usize>) -> (String, Option<(usize, usize)>) {
+    let spans = location.map(|_| analyze::spans(&doc.text)).unwrap_or_default();
+    let matched = location.and_then(|location| Some((location, *spans.get(location)?)));
+    if let Some((location, (match_start, match_end))) = matched {
+        let (start, _) = spans[location.saturating_sub(CONTEXT_WINDOW)];
+        let (_, end) = spans[(location + CONTEXT_WINDOW).min(spans.len() - 1)];
+        let snippet: String = doc.text
+            .chars()
+            .skip(start)
+            .take(end - start)
+            // one char for another, so the highlight offsets still hold
+            .map(|c| if c == '\n' { ' ' } else { c })
+            .collect();
+        return (snippet, Some((match_start - start, match_end - start)));
     }
+    // Only matched in a field, so the description is the best summary we have,
+    // or failing that whatever field comes first (json documents have no body)
+    let summary = [extract::DESCRIPTION, extract::OG_DESCRIPTION, extract::TITLE]
+        .iter()
+        .find_map(|field| doc.fields.get(*field))
+        .or_else(|| doc.fields.values().next())
+        .cloned()
+        .unwrap_or_default();
+    (summary, None)
+}
 
-    return results;
+// Only documents in one of languages are returned, if any are given, and
+// documents in the preferred language rank higher
+fn retrieve_from_index(query: Query, languages: &[String], preferred: &str, store: &Store) -> Vec<QueryResponse> {
+    // Scores don't depend on the rest of the collection, so each segment is
+    // scored on its own
+    let mut hits: Vec<_> = store.segments()
+        .flat_map(|(index, deletions)| {
+            score_query(&query, index)
+                .into_iter()
+                .filter(move |(doc, _)| !deletions.contains(*doc))
+                .map(move |(doc, hit)| (index, doc, hit))
+        })
+        .filter(|(index, doc, _)| languages.is_empty() || index.language(*doc).is_some_and(|l| languages.iter().any(|want| want == l)))
+        .map(|(index, doc, mut hit)| {
+            if index.language(doc) == Some(preferred) {
+                hit.score *= LANGUAGE_BOOST;
+            }
+            (index, doc, hit)
+        })
+        .collect();
+    hits.sort_by(|(_, _, a), (_, _, b)| b.score.total_cmp(&a.score));
+
+    hits.into_iter()
+        .take(MAX_RESULTS)
+        .map(|(index, doc_id, hit)| {
+            let doc = index.document(doc_id);
+            let (context, highlight) = doc.as_ref().map(|doc| context(doc, hit.location)).unwrap_or_default();
+            QueryResponse {
+                ens_name: "???".to_string(),
+                title: doc.as_ref().and_then(|doc| doc.fields.get(extract::TITLE).cloned()),
+                context,
+                highlight,
+                language: doc.and_then(|doc| doc.language),
+                ipfs_hash: index.doc_hash(doc_id).to_string(),
+                score: hit.score,
+            }
+        })
+        .collect()
 }
 
 // async fn handle_query(query: Query, index: &index::Index) -> Result<impl warp::Reply, warp::Rejection> {
@@ -89,23 +253,44 @@ fn retrieve_from_index(query: Query, index: &index::Index, docs: &Docs) -> Vec<Q
 //     Ok(json_reply)
 // }
 
-fn parse_query(query_str: String) -> Query {
+// lang:xx restricts results to documents in that language rather than matching
+// a field, so those words are taken out before the rest is parsed
+fn split_languages(query_str: &str) -> (String, Vec<String>) {
+    let mut languages = Vec::new();
+    let rest: Vec<&str> = query_str
+        .split(' ')
+        .filter(|word| match word.strip_prefix("lang:") {
+            Some(code) if !code.is_empty() => {
+                languages.push(code.to_lowercase());
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    (rest.join(" "), languages)
+}
+
+fn parse_query(query_str: String, analyzers: &[Analyzer]) -> Query {
     let words: Vec<_> = query_str.split(" ").collect();
-    if words.len() == 1 {
-        // base case single word
-        return Query { subquery1: None, subquery2: None, connector: None, base: Some(query_str) }
-    } else if query_str.starts_with('"') && query_str.ends_with('"') {
+    // "a b" OR "c d" starts and ends with a quote too
+    let quoted = query_str.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')).filter(|inner| !inner.contains('"'));
+    if let Some(quoted) = quoted {
         // exact match base case
-        return Query { subquery1: None, subquery2: None, connector: None, base: Some(query_str) }
+        return phrase_query(quoted, analyzers);
+    } else if words.len() == 1 {
+        // base case single word
+        return base_query(query_str, analyzers);
     }
 
     for (i, word) in words.iter().enumerate() {
         if word.to_uppercase().as_str() == "AND" && i > 0 && i < words.len() - 1 {
             return Query {
-                subquery1: Some(Box::new(parse_query(words[..i].join(" ")))),
-                subquery2: Some(Box::new(parse_query(words[i + 1..].join(" ")))),
+                subquery1: Some(Box::new(parse_query(words[..i].join(" "), analyzers))),
+                subquery2: Some(Box::new(parse_query(words[i + 1..].join(" "), analyzers))),
                 connector: Some(QueryToken::And),
-                base: None
+                base: None,
+                field: None,
+                phrase: None,
             }
         }
     }
@@ -113,79 +298,183 @@ fn parse_query(query_str: String) -> Query {
     for (i, word) in words.iter().enumerate() {
         if word.to_uppercase().as_str() == "OR" && i > 0 && i < words.len() - 1 {
             return Query {
-                subquery1: Some(Box::new(parse_query(words[..i].join(" ")))),
-                subquery2: Some(Box::new(parse_query(words[i + 1..].join(" ")))),
+                subquery1: Some(Box::new(parse_query(words[..i].join(" "), analyzers))),
+                subquery2: Some(Box::new(parse_query(words[i + 1..].join(" "), analyzers))),
                 connector: Some(QueryToken::Or),
-                base: None
+                base: None,
+                field: None,
+                phrase: None,
             }
         }
     }
 
-    return Query {
-        subquery1: Some(Box::new(parse_query(words[0].to_string()))),
-        subquery2: Some(Box::new(parse_query(words[1..].join(" ")))),
+    Query {
+        subquery1: Some(Box::new(parse_query(words[0].to_string(), analyzers))),
+        subquery2: Some(Box::new(parse_query(words[1..].join(" "), analyzers))),
         connector: Some(QueryToken::Or),
-        base: None
+        base: None,
+        field: None,
+        phrase: None,
     }
 }
 
-fn tokenize_query(query: String) -> Vec<QueryToken> {
-    let mut tokens = Vec::new();
-    let re = Regex::new(r#""[^"]*"|\S+"#).unwrap();
-
-    for token in re.find_iter(&*query) {
-        let mut token = token.as_str();
-        if token.starts_with('"') && token.ends_with('"') {
-            // Remove the surrounding quotes from phrases
-            token = &token[1..token.len() - 1];
-            tokens.push(QueryToken::Phrase(token.to_string()));
-        } else {
-            match token.to_uppercase().as_str() {
-                "AND" => tokens.push(QueryToken::And),
-                "OR" => tokens.push(QueryToken::Or),
-                _ => tokens.push(QueryToken::Word(token.to_string())),
-            }
+// A word, or field:word to only match the word in that field, e.g.
+// title:uniswap or json.attributes.value:gold. The word goes through the same
+// analyzer as the documents did; if that splits it, as it does for chinese
+// or japanese, all of its terms have to match. Documents are stemmed in their
+// own language, so there's an analyzer per language the word may be in and
+// matching any of them is enough.
+fn base_query(word: String, analyzers: &[Analyzer]) -> Query {
+    let scoped = word.split_once(':').filter(|(field, term)| {
+        !field.is_empty()
+            && !term.is_empty()
+            && !term.starts_with("//")
+            && field.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+    });
+    let (base, field) = match scoped {
+        Some((field, term)) => (term, Some(field.to_lowercase())),
+        None => (word.as_str(), None),
+    };
+    // Addresses, ENS names, $TICKERs and CIDs are looked up in their own fields
+    let entity = entity::parse(base).filter(|entity| field.as_deref().is_none_or(|field| field == entity.field));
+    if let Some(entity) = entity {
+        return Query { subquery1: None, subquery2: None, connector: None, base: Some(entity.term), field: Some(entity.field.to_string()), phrase: None };
+    }
+    let term_query = |term: String| Query { subquery1: None, subquery2: None, connector: None, base: Some(term), field: field.clone(), phrase: None };
+    let connect = |connector: QueryToken| {
+        move |left: Query, right: Query| Query {
+            subquery1: Some(Box::new(left)),
+            subquery2: Some(Box::new(right)),
+            connector: Some(connector),
+            base: None,
+            field: None,
+            phrase: None,
+        }
+    };
+    let mut alternatives: Vec<Vec<String>> = Vec::new();
+    for analyzer in analyzers {
+        let terms: Vec<String> = analyzer.analyze(base, &analyzer.language).map(|(_, term)| term).collect();
+        // Nothing left means a stopword in that language
+        if !terms.is_empty() && !alternatives.contains(&terms) {
+            alternatives.push(terms);
         }
     }
+    alternatives
+        .into_iter()
+        .map(|terms| terms.into_iter().map(term_query).reduce(connect(QueryToken::And)).unwrap())
+        .reduce(connect(QueryToken::Or))
+        .unwrap_or_else(|| term_query(String::new()))
+}
 
-    tokens
+// The words of a quoted query, in order and next to each other. They go
+// through the analyzer like any other words, so stopwords in the phrase are
+// dropped but still count towards the distance between the terms around them.
+// One phrase per language the analyzers stem it differently for, and matching
+// any of them is enough.
+fn phrase_query(text: &str, analyzers: &[Analyzer]) -> Query {
+    let mut alternatives: Vec<Vec<(u32, String)>> = Vec::new();
+    for analyzer in analyzers {
+        let terms: Vec<(usize, String)> = analyzer.analyze(text, &analyzer.language).collect();
+        let Some((first, _)) = terms.first() else { continue };
+        let first = *first;
+        let phrase = terms.into_iter().map(|(i, term)| ((i - first) as u32, term)).collect();
+        if !alternatives.contains(&phrase) {
+            alternatives.push(phrase);
+        }
+    }
+    alternatives
+        .into_iter()
+        .map(|phrase| Query { subquery1: None, subquery2: None, connector: None, base: None, field: None, phrase: Some(phrase) })
+        .reduce(|left, right| Query {
+            subquery1: Some(Box::new(left)),
+            subquery2: Some(Box::new(right)),
+            connector: Some(QueryToken::Or),
+            base: None,
+            field: None,
+            phrase: None,
+        })
+        // nothing but stopwords
+        .unwrap_or(Query { subquery1: None, subquery2: None, connector: None, base: Some(String::new()), field: None, phrase: None })
 }
 
-// fn process_query(query: String, index: &index::Index) -> Vec<QueryResponse> {
-//     let tokens = tokenize_query(query);
-//     for token in tokens {
-//         match token {
-//             QueryToken::Word(word) => println!("Word: {}", word),
-//             QueryToken::Phrase(phrase) => println!("Phrase: {}", phrase),
-//             QueryToken::And => println!("AND"),
-//             QueryToken::Or => println!("OR"),
-//         }
-//     }
-//     vec![
-//         QueryResponse { ens_name: "dummy.eth".to_string(), ipfs_hash: "QmbQ7z3CftrbvKYXsA4iDkjWVUB5nX2tRJdJgYYWJTJfja".to_string(), context: "context".to_string() },
-//         QueryResponse { ens_name: "dummy.eth".to_string(), ipfs_hash: "QmbQ7z3CftrbvKYXsA4iDkjWVUB5nX2tRJdJgYYWJTJfja".to_string(), context: "context".to_string() },
-//     ]
-// }
+// The context with the matched word in brackets, for the terminal
+fn highlighted(context: &str, highlight: Option<(usize, usize)>) -> String {
+    let Some((start, end)) = highlight else { return context.to_string() };
+    let chars: Vec<char> = context.chars().collect();
+    let part = |from: usize, to: usize| chars[from..to].iter().collect::<String>();
+    format!("{}[{}]{}", part(0, start), part(start, end), part(end, chars.len()))
+}
 
-fn receive_search(index: &index::Index, docs: &Docs) {
-    while true {
+fn receive_search(store: &RwLock<Store>, analyzer: &Analyzer) {
+    loop {
         let mut input = String::new();
         print!("Please enter something: ");
         stdout().flush().unwrap(); // Make sure the prompt is immediately displayed
         stdin().read_line(&mut input).unwrap();
-        let query = parse_query(input.clone());
-        retrieve_from_index(query, index, docs);
+        let (query_str, languages) = split_languages(input.trim());
+        // Stem the query the way documents in the wanted languages were
+        // stemmed, or in every language in the index if none are given
+        let query_languages = if languages.is_empty() {
+            let mut indexed = store.read().unwrap().languages().clone();
+            indexed.insert(analyzer.language.clone());
+            indexed.into_iter().collect()
+        } else {
+            languages.clone()
+        };
+        let analyzers: Vec<Analyzer> = query_languages.iter().map(|language| analyzer.with_language(language)).collect();
+        let query = parse_query(query_str, &analyzers);
+        let results = retrieve_from_index(query, &languages, &analyzer.language, &store.read().unwrap());
         println!("You entered: {}", input.trim());
+        for result in results {
+            println!("{:.1} {} {} - {}", result.score, result.ipfs_hash,
+                     result.title.unwrap_or_default(), highlighted(&result.context, result.highlight));
+        }
     }
 }
 
 #[tokio::main]
 async fn main() {
-    let (index, docs) = index::load_index(true).await;
+    let config = Config::from_env();
+    // In-house extractors get registered here and take precedence over the built in ones
+    let extractors = Arc::new(extract::Registry::default());
+    // `ens-search verify` reads the whole index to check it against its
+    // checksums, rather than serving it
+    if std::env::args().nth(1).as_deref() == Some("verify") {
+        if let Err(e) = index::verify_index() {
+            println!("{:#}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    // Pass true to rebuild the index from scratch
+    let store = match index::open_index(&config, false) {
+        Ok(store) => store,
+        Err(e) => {
+            println!("{:#}", e);
+            process::exit(1);
+        }
+    };
     // let index = HashMap::new();
-    println!("Loaded index with {} entries", index.len());
+    println!("Loaded index with {} documents in {} segments", store.doc_count(), store.segment_count());
 
-    receive_search(&index, &docs);
+    // Searches start on the index as it is and see what the update indexes
+    // once it's committed. Only indexes what changed since the last run.
+    // Merging waits for the update, so only one merge runs at a time.
+    let store = Arc::new(RwLock::new(store));
+    let updating = Arc::clone(&store);
+    let update_config = config.clone();
+    tokio::spawn(async move {
+        // The chain or the gateways being down only matters if there's nothing to search yet
+        if let Err(e) = index::update_index(&update_config, extractors, &updating).await {
+            if updating.read().unwrap().segment_count() == 0 {
+                println!("Could not build the index: {:#}", e);
+                process::exit(1);
+            }
+            println!("Could not update the index, searching it as it is: {:#}", e);
+        }
+        store::merge_in_background(updating);
+    });
+    receive_search(&store, &config.analyzer);
 //     let query_route = warp::post()
 //         .and(warp::path("query"))
 //         .and(warp::body::json::<Query>())
@@ -197,3 +486,61 @@ async fn main() {
 //         .await;
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use crate::analyze::Filter;
+    use crate::index::Docs;
+    use crate::postings::{DocTerms, Index};
+
+    fn analyzer() -> Analyzer {
+        Analyzer::new(vec![Filter::CaseFold, Filter::TrimPunctuation, Filter::Stopwords, Filter::Stem], "en".to_string())
+    }
+
+    // One document per body, analyzed the way builds do it
+    fn segment(dir: &Path, bodies: &[&str]) -> Segment {
+        let analyzer = analyzer();
+        let mut index = Index::new();
+        let mut docs = Docs::new();
+        for (i, body) in bodies.iter().enumerate() {
+            let mut terms = DocTerms::new();
+            for (position, term) in analyzer.analyze(body, "en") {
+                terms.entry(term).or_default().entry(extract::BODY.to_string()).or_default().push(position as u32);
+            }
+            index.add(format!("Qm{}", i), terms);
+            docs.insert(format!("Qm{}", i), Document { text: body.to_string(), ..Document::default() });
+        }
+        let path = dir.join("test.seg");
+        disk::save_segment(&path, &index, &docs).unwrap();
+        Segment::open(&path).unwrap()
+    }
+
+    fn search(segment: &Segment, query: &str) -> Vec<(DocId, Option<usize>)> {
+        let query = parse_query(query.to_string(), &[analyzer()]);
+        let mut found: Vec<_> = score_query(&query, segment).into_iter().map(|(doc, hit)| (doc, hit.location)).collect();
+        found.sort_by_key(|(doc, _)| *doc);
+        found
+    }
+
+    #[test]
+    fn quoted_queries_match_adjacent_terms() {
+        let dir = tempfile::tempdir().unwrap();
+        let segment = segment(dir.path(), &[
+            "Built by Uniswap Labs.",
+            "Labs that build on Uniswap",
+            "The Bank of England",
+            "bank, england",
+        ]);
+        // analyzed like the documents, so case and punctuation don't matter
+        assert_eq!(search(&segment, "\"Uniswap Labs\""), vec![(0, Some(2))]);
+        assert_eq!(search(&segment, "\"uniswap labs\""), vec![(0, Some(2))]);
+        assert_eq!(search(&segment, "uniswap labs"), vec![(0, Some(2)), (1, Some(4))]);
+        // the stopword is dropped but still takes up its position
+        assert_eq!(search(&segment, "\"bank of england\""), vec![(2, Some(1))]);
+        assert_eq!(search(&segment, "\"labs uniswap\""), vec![]);
+        assert_eq!(search(&segment, "\"uniswap labs\" OR \"bank england\""), vec![(0, Some(2)), (3, Some(0))]);
+        assert_eq!(search(&segment, "\"the of\""), vec![]);
+    }
+}
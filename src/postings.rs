@@ -0,0 +1,283 @@
+use std::collections::{BTreeMap, HashMap};
+
+pub(crate) type DocId = u32;
+pub(crate) type FieldId = u32;
+
+// One document's terms: term -> field -> positions, in increasing order
+pub(crate) type DocTerms = HashMap<String, BTreeMap<String, Vec<u32>>>;
+
+// Where a term occurs in one field of one document
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Posting {
+    pub(crate) doc: DocId,
+    pub(crate) field: FieldId,
+    pub(crate) positions: Vec<u32>,
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], at: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.get(*at)?;
+        *at += 1;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// Postings sorted by document then field, each encoded as the doc id delta,
+// the field id, the number of positions and the position deltas, all varints
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PostingList {
+    // how many postings, not bytes
+    len: u32,
+    last_doc: DocId,
+    bytes: Vec<u8>,
+}
+
+impl PostingList {
+    pub(crate) fn from_postings(postings: &[Posting]) -> Self {
+        let mut list = PostingList::default();
+        for posting in postings {
+            list.push(posting.doc, posting.field, &posting.positions);
+        }
+        list
+    }
+
+    // Callers add documents in id order and positions in increasing order, so
+    // this only ever appends. Anything else would encode a wrapped delta.
+    fn push(&mut self, doc: DocId, field: FieldId, positions: &[u32]) {
+        let last_doc = if self.len == 0 { 0 } else { self.last_doc };
+        let delta = doc.checked_sub(last_doc).expect("Postings pushed out of document order");
+        write_varint(&mut self.bytes, delta);
+        write_varint(&mut self.bytes, field);
+        write_varint(&mut self.bytes, positions.len() as u32);
+        let mut previous = 0;
+        for position in positions {
+            write_varint(&mut self.bytes, position.checked_sub(previous).expect("Positions out of order"));
+            previous = *position;
+        }
+        self.len += 1;
+        self.last_doc = doc;
+    }
+
+    pub(crate) fn iter(&self) -> PostingIter<'_> {
+        PostingIter::new(&self.bytes, self.len)
+    }
+
+    // The number of postings and their encoding, for writing segments
+    pub(crate) fn encoded(&self) -> (u32, &[u8]) {
+        (self.len, &self.bytes)
+    }
+}
+
+pub(crate) struct PostingIter<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    doc: DocId,
+    remaining: u32,
+}
+
+impl<'a> PostingIter<'a> {
+    pub(crate) fn new(bytes: &'a [u8], len: u32) -> Self {
+        PostingIter { bytes, at: 0, doc: 0, remaining: len }
+    }
+}
+
+impl Iterator for PostingIter<'_> {
+    type Item = Posting;
+
+    fn next(&mut self) -> Option<Posting> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        // Checked, since the bytes come from a file that may be corrupt
+        self.doc = self.doc.checked_add(read_varint(self.bytes, &mut self.at)?)?;
+        let field = read_varint(self.bytes, &mut self.at)?;
+        let count = read_varint(self.bytes, &mut self.at)?;
+        let mut positions = Vec::with_capacity((count as usize).min(self.bytes.len()));
+        let mut position = 0;
+        for _ in 0..count {
+            position = read_varint(self.bytes, &mut self.at).and_then(|delta| delta.checked_add(position))?;
+            positions.push(position);
+        }
+        Some(Posting { doc: self.doc, field, positions })
+    }
+}
+
+// A positional inverted index: documents and fields are numbered, and the term
+// dictionary maps each term to its compressed posting list. This is what a
+// build accumulates; searches go through the segment it's written to.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Index {
+    // content hash of each document, by doc id
+    doc_hashes: Vec<String>,
+    // field name by field id, and the other way around
+    fields: Vec<String>,
+    field_ids: HashMap<String, FieldId>,
+    terms: BTreeMap<String, PostingList>,
+}
+
+impl Index {
+    pub(crate) fn new() -> Self {
+        Index::default()
+    }
+
+    fn field_id(&mut self, field: &str) -> FieldId {
+        if let Some(id) = self.field_ids.get(field) {
+            return *id;
+        }
+        let id = self.fields.len() as FieldId;
+        self.fields.push(field.to_string());
+        self.field_ids.insert(field.to_string(), id);
+        id
+    }
+
+    // Adds a document and returns its id
+    pub(crate) fn add(&mut self, hash: String, terms: DocTerms) -> DocId {
+        let doc = self.doc_hashes.len() as DocId;
+        self.doc_hashes.push(hash);
+        for (term, fields) in terms {
+            for (field, mut positions) in fields {
+                // Positions are delta encoded, but two sources can feed the
+                // same field, like entities in a field an extractor also set
+                positions.sort_unstable();
+                positions.dedup();
+                let field = self.field_id(&field);
+                self.terms.entry(term.clone()).or_default().push(doc, field, &positions);
+            }
+        }
+        doc
+    }
+
+    // Adds a term's postings, as (doc, field, positions), to documents that
+    // are already in the index. Slower than add, since the posting list has to
+    // be rebuilt to keep it sorted, so everything for the term comes at once.
+    pub(crate) fn insert(&mut self, term: String, added: Vec<(DocId, String, Vec<u32>)>) {
+        let mut added: Vec<Posting> = added
+            .into_iter()
+            .map(|(doc, field, positions)| Posting { doc, field: self.field_id(&field), positions })
+            .collect();
+        let list = self.terms.entry(term).or_default();
+        let mut postings: Vec<Posting> = list.iter().collect();
+        postings.append(&mut added);
+        postings.sort_by_key(|posting| (posting.doc, posting.field));
+        *list = PostingList::from_postings(&postings);
+    }
+
+    pub(crate) fn terms(&self) -> impl Iterator<Item = (&String, &PostingList)> {
+        self.terms.iter()
+    }
+
+    pub(crate) fn doc_hash(&self, doc: DocId) -> &str {
+        &self.doc_hashes[doc as usize]
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.doc_hashes.is_empty()
+    }
+
+    pub(crate) fn doc_hashes(&self) -> impl Iterator<Item = (DocId, &String)> {
+        self.doc_hashes.iter().enumerate().map(|(doc, hash)| (doc as DocId, hash))
+    }
+
+    pub(crate) fn field_name(&self, field: FieldId) -> &str {
+        &self.fields[field as usize]
+    }
+
+    pub(crate) fn fields(&self) -> &[String] {
+        &self.fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc_terms(entries: &[(&str, &str, &[u32])]) -> DocTerms {
+        let mut terms = DocTerms::new();
+        for (term, field, positions) in entries {
+            terms.entry(term.to_string()).or_default().insert(field.to_string(), positions.to_vec());
+        }
+        terms
+    }
+
+    #[test]
+    fn posting_list_round_trips() {
+        let postings = vec![
+            Posting { doc: 0, field: 0, positions: vec![0, 3, 200] },
+            Posting { doc: 0, field: 2, positions: vec![] },
+            Posting { doc: 7, field: 1, positions: vec![5] },
+            Posting { doc: 100_000, field: 0, positions: vec![1, 2, 70_000] },
+        ];
+        let list = PostingList::from_postings(&postings);
+        assert_eq!(list.iter().collect::<Vec<_>>(), postings);
+        assert_eq!(list.encoded().0, 4);
+    }
+
+    #[test]
+    fn iter_stops_at_truncated_bytes() {
+        let list = PostingList::from_postings(&[Posting { doc: 3, field: 0, positions: vec![1, 300] }]);
+        let (len, bytes) = list.encoded();
+        assert_eq!(PostingIter::new(&bytes[..bytes.len() - 1], len).count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of document order")]
+    fn push_rejects_earlier_documents() {
+        let mut list = PostingList::default();
+        list.push(5, 0, &[]);
+        list.push(4, 0, &[]);
+    }
+
+    #[test]
+    fn add_sorts_positions_and_reuses_field_ids() {
+        let mut index = Index::new();
+        index.add("a".to_string(), doc_terms(&[("eth", "ticker", &[1, 0, 1]), ("gm", "body", &[2])]));
+        let doc = index.add("b".to_string(), doc_terms(&[("eth", "ticker", &[4])]));
+        assert_eq!(doc, 1);
+        assert_eq!(index.fields().len(), 2);
+
+        let (_, eth) = index.terms().find(|(term, _)| *term == "eth").unwrap();
+        let ticker = index.fields().iter().position(|field| field == "ticker").unwrap() as FieldId;
+        assert_eq!(
+            eth.iter().collect::<Vec<_>>(),
+            vec![
+                Posting { doc: 0, field: ticker, positions: vec![0, 1] },
+                Posting { doc: 1, field: ticker, positions: vec![4] },
+            ]
+        );
+    }
+
+    #[test]
+    fn insert_keeps_postings_sorted() {
+        let mut index = Index::new();
+        index.add("a".to_string(), doc_terms(&[("gm", "body", &[0])]));
+        index.add("b".to_string(), doc_terms(&[("gm", "body", &[1])]));
+        index.add("c".to_string(), DocTerms::new());
+        index.insert("gm".to_string(), vec![(2, "alt_text".to_string(), vec![0]), (0, "alt_text".to_string(), vec![3])]);
+
+        let (_, gm) = index.terms().find(|(term, _)| *term == "gm").unwrap();
+        let docs: Vec<_> = gm.iter().map(|posting| (posting.doc, index.field_name(posting.field).to_string())).collect();
+        assert_eq!(
+            docs,
+            vec![
+                (0, "body".to_string()),
+                (0, "alt_text".to_string()),
+                (1, "body".to_string()),
+                (2, "alt_text".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashSet;
+use std::process::Stdio;
+use std::sync::{Arc, OnceLock};
+use anyhow::{anyhow, bail};
+use futures::future::BoxFuture;
+use regex::Regex;
+use reqwest::{Client, Url};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::task;
+use tokio::time::{timeout, Duration};
+use crate::charset;
+use crate::config::{BodyLimits, Config, RenderMode, RetryPolicy};
+use crate::extract::html;
+use crate::fetch::{self, Fetched};
+use crate::sniff;
+
+// Scripts beyond this are usually vendor chunks with nothing to read
+const MAX_SCRIPTS: usize = 8;
+// String literals outside this length range are rarely prose
+const MIN_LITERAL_LEN: usize = 8;
+const MAX_LITERAL_LEN: usize = 500;
+// Directives every bundle is full of
+const DIRECTIVES: &[&str] = &["use strict", "use client", "use server"];
+
+// Turns the static html of a client side app into html with its content
+pub(crate) trait Renderer: Send + Sync {
+    // An error means the static html is indexed as it is
+    fn render<'a>(&'a self, url: &'a str, html: &'a str) -> BoxFuture<'a, Result<String, anyhow::Error>>;
+}
+
+// Runs an external program with the page url as its last argument and the
+// static html on stdin, and takes the rendered html from its stdout. The
+// default, `chromium --headless --dump-dom`, ignores stdin and loads the url.
+pub(crate) struct CommandRenderer {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl CommandRenderer {
+    fn new(command: &str, timeout: Duration) -> Option<Self> {
+        let mut parts = command.split_whitespace().map(String::from);
+        let program = parts.next()?;
+        Some(CommandRenderer { program, args: parts.collect(), timeout })
+    }
+
+    async fn run(&self, url: &str, html: &str) -> Result<String, anyhow::Error> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("No stdin for renderer"))?;
+        let input = html.as_bytes().to_vec();
+        // Written alongside reading stdout, so a chatty renderer can't deadlock us.
+        // Renderers that don't read stdin close it early, which isn't an error.
+        let writer = async move {
+            let _ = stdin.write_all(&input).await;
+        };
+        let (_, output) = tokio::join!(writer, child.wait_with_output());
+        let output = output?;
+        if !output.status.success() {
+            bail!("{} exited with {}", self.program, output.status);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+impl Renderer for CommandRenderer {
+    fn render<'a>(&'a self, url: &'a str, html: &'a str) -> BoxFuture<'a, Result<String, anyhow::Error>> {
+        Box::pin(async move {
+            timeout(self.timeout, self.run(url, html))
+                .await
+                .map_err(|_| anyhow!("Renderer timed out after {} seconds", self.timeout.as_secs()))?
+        })
+    }
+}
+
+// The lightweight alternative to a browser: fetches the page's scripts and web
+// app manifest and appends the human readable strings found in them to the page.
+// Renders run while the page's task holds its gateway permit, so these fetches
+// count against the same per gateway limit as the page.
+pub(crate) struct BundleRenderer {
+    client: Client,
+    retry: RetryPolicy,
+    body_limits: BodyLimits,
+}
+
+fn literal_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(&format!(
+            r#""((?:[^"\\\n]|\\.){{{min},{max}}})"|'((?:[^'\\\n]|\\.){{{min},{max}}})'|`((?:[^`\\]|\\.){{{min},{max}}})`"#,
+            min = MIN_LITERAL_LEN,
+            max = MAX_LITERAL_LEN
+        ))
+        .unwrap()
+    })
+}
+
+fn unescape(literal: &str) -> String {
+    let mut out = String::with_capacity(literal.len());
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('r') | Some('t') => out.push(' '),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                if let Some(c) = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                    out.push(c);
+                }
+            }
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+// Whether a string literal reads like text meant for people rather than code,
+// css classes, urls or identifiers
+fn is_prose(text: &str) -> bool {
+    let words: Vec<_> = text.split_whitespace().collect();
+    if DIRECTIVES.contains(&text) || words.len() < 2 || !words.iter().any(|w| w.chars().filter(|c| c.is_alphabetic()).count() >= 3) {
+        return false;
+    }
+    if text.contains("://") || text.contains("${") || text.chars().any(|c| "{}<>=;|\\".contains(c)) {
+        return false;
+    }
+    let letters = text.chars().filter(|c| c.is_alphabetic() || c.is_whitespace()).count();
+    if letters * 10 < text.chars().count() * 8 {
+        return false;
+    }
+    // "flex items-center justify-between" and the like
+    let code_like = words
+        .iter()
+        .filter(|w| w.trim_end_matches(['.', ',', ':']).contains(['-', '_', ':', '/', '.']))
+        .count();
+    code_like * 2 <= words.len()
+}
+
+fn script_strings(source: &str, strings: &mut Vec<String>) {
+    for captures in literal_regex().captures_iter(source) {
+        if let Some(literal) = captures.iter().skip(1).flatten().next() {
+            let text = unescape(literal.as_str());
+            if is_prose(&text) {
+                strings.push(text.split_whitespace().collect::<Vec<_>>().join(" "));
+            }
+        }
+    }
+}
+
+fn manifest_strings(source: &str, strings: &mut Vec<String>) {
+    if let Ok(manifest) = serde_json::from_str::<serde_json::Value>(source) {
+        for key in ["name", "short_name", "description"] {
+            if let Some(value) = manifest.get(key).and_then(|v| v.as_str()) {
+                strings.push(value.to_string());
+            }
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// The site is served from a path on the gateway (/ipfs/<cid>/), so paths that
+// are absolute on the site are relative to that rather than the gateway root
+fn resolve(base: &Url, src: &str) -> Option<Url> {
+    match src.strip_prefix('/') {
+        Some(path) if !path.starts_with('/') => base.join(path).ok(),
+        _ => base.join(src).ok(),
+    }
+}
+
+impl BundleRenderer {
+    async fn fetch_text(&self, url: &Url) -> Result<String, anyhow::Error> {
+        let fetched = fetch::fetch_document(&self.client, &self.retry, &self.body_limits, url.as_str()).await?;
+        Ok(charset::decode(&fetched.content_type, &fetched.body))
+    }
+
+    async fn run(&self, url: &str, page: &str) -> Result<String, anyhow::Error> {
+        // Gateway urls for a site root have no trailing slash, but relative
+        // asset paths are relative to the site root
+        let base = if url.ends_with('/') { Url::parse(url)? } else { Url::parse(&format!("{}/", url))? };
+        let assets = html::assets(page);
+
+        let mut strings = Vec::new();
+        if let Some(href) = assets.manifest.and_then(|href| resolve(&base, &href)) {
+            match self.fetch_text(&href).await {
+                Ok(source) => manifest_strings(&source, &mut strings),
+                Err(e) => println!("Could not fetch manifest {}: {}", href, e),
+            }
+        }
+        let scripts = assets
+            .scripts
+            .iter()
+            .filter_map(|src| resolve(&base, src))
+            // third party scripts (analytics, CDNs) say nothing about the site
+            .filter(|script| script.origin() == base.origin())
+            .take(MAX_SCRIPTS);
+        for script in scripts {
+            match self.fetch_text(&script).await {
+                Ok(source) => script_strings(&source, &mut strings),
+                Err(e) => println!("Could not fetch script {}: {}", script, e),
+            }
+        }
+
+        let mut seen = HashSet::new();
+        strings.retain(|s| seen.insert(s.clone()));
+        if strings.is_empty() {
+            bail!("No text found in the page's scripts or manifest");
+        }
+
+        let content: String = strings.iter().map(|s| format!("<p>{}</p>\n", escape(s))).collect();
+        Ok(match page.rfind("</body>") {
+            Some(end) => format!("{}<div>\n{}</div>{}", &page[..end], content, &page[end..]),
+            None => format!("{}<div>\n{}</div>", page, content),
+        })
+    }
+}
+
+impl Renderer for BundleRenderer {
+    fn render<'a>(&'a self, url: &'a str, html: &'a str) -> BoxFuture<'a, Result<String, anyhow::Error>> {
+        Box::pin(self.run(url, html))
+    }
+}
+
+pub(crate) fn from_config(config: &Config, client: &Client) -> Option<Arc<dyn Renderer>> {
+    match &config.render.mode {
+        RenderMode::Static => None,
+        RenderMode::Bundle => Some(Arc::new(BundleRenderer {
+            client: client.clone(),
+            retry: config.retry.clone(),
+            body_limits: config.body_limits.clone(),
+        })),
+        RenderMode::Command(command) => match CommandRenderer::new(command, config.render.timeout) {
+            Some(renderer) => Some(Arc::new(renderer)),
+            None => {
+                println!("RENDER_COMMAND is empty, indexing static html only");
+                None
+            }
+        },
+    }
+}
+
+// Renders html app shells, leaving every other document alone. Falls back to
+// the static html when rendering fails.
+pub(crate) async fn render_if_needed(renderer: &dyn Renderer, limit: &Semaphore, url: &str, fetched: Fetched) -> Fetched {
+    let check = fetched.clone();
+    let shell = task::spawn_blocking(move || {
+        let content_type = sniff::content_type(&check.content_type, check.name.as_deref(), &check.body);
+        if content_type != "text/html" {
+            return None;
+        }
+        let page = charset::decode(&check.content_type, &check.body);
+        html::is_app_shell(&page).then_some(page)
+    });
+    let Ok(Some(page)) = shell.await else { return fetched };
+
+    let _permit = limit.acquire().await.unwrap();
+    match renderer.render(url, &page).await {
+        Ok(rendered) => {
+            println!("Rendered {}", url);
+            Fetched {
+                content_type: "text/html; charset=utf-8".to_string(),
+                name: fetched.name,
+                body: rendered.into(),
+                truncated: false,
+                limit: fetched.limit,
+            }
+        }
+        Err(e) => {
+            println!("Could not render {}, indexing the static html: {}", url, e);
+            fetched
+        }
+    }
+}
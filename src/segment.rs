@@ -0,0 +1,493 @@
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, Error, Write};
+use std::path::Path;
+use memmap2::Mmap;
+use crate::index::{Docs, Document};
+use crate::postings::{DocId, FieldId, Index, Posting, PostingIter, PostingList};
+
+// A segment is a read only index file that's memory mapped rather than read,
+// so opening one is instant and it can be bigger than memory.
+//
+// Layout: MAGIC, then the tables' data and offsets, then the footer. Each
+// table is a list of byte strings: their data back to back, followed by
+// count + 1 little endian u64 offsets into that data. The footer has each
+// table's data position, offsets position, count and crc32, a crc32 of all
+// that, the format version, and MAGIC again so a truncated file is caught.
+// Opening only checks the footer, so it stays instant; verify reads the
+// tables to check them against their crc32s.
+const MAGIC: &[u8; 8] = b"ENSSEG\0\0";
+pub(crate) const SEGMENT_VERSION: u32 = 2;
+
+// Tables, by their place in the footer. Doc tables are by doc id, fields by
+// field id, and postings are parallel to the sorted terms.
+const DOC_HASHES: usize = 0;
+const LANGUAGES: usize = 1;
+const DOCUMENTS: usize = 2;
+const FIELDS: usize = 3;
+const TERMS: usize = 4;
+const POSTINGS: usize = 5;
+const TABLES: usize = 6;
+const TABLE_NAMES: [&str; TABLES] = ["document hashes", "languages", "documents", "fields", "terms", "postings"];
+
+const TABLE_FOOTER_LEN: usize = 3 * 8 + 4;
+const FOOTER_LEN: usize = TABLES * TABLE_FOOTER_LEN + 4 + 4 + MAGIC.len();
+
+fn invalid(message: &str) -> Error {
+    Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+// Tracks where the writer is, since tables refer to each other by position,
+// and checksums what's written since the last reset
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        CountingWriter { inner, written: 0, hasher: crc32fast::Hasher::new() }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)?;
+        self.written += bytes.len() as u64;
+        self.hasher.update(bytes);
+        Ok(())
+    }
+
+    fn checksum(&mut self) -> u32 {
+        std::mem::take(&mut self.hasher).finalize()
+    }
+}
+
+// Returns the table's footer entry
+fn write_table<W: Write, B: AsRef<[u8]>>(
+    writer: &mut CountingWriter<W>,
+    entries: impl Iterator<Item = B>,
+) -> io::Result<Table> {
+    let data = writer.written;
+    writer.checksum();
+    let mut offsets = vec![0u64];
+    for entry in entries {
+        writer.write(entry.as_ref())?;
+        offsets.push(writer.written - data);
+    }
+    let index = writer.written;
+    for offset in &offsets {
+        writer.write(&offset.to_le_bytes())?;
+    }
+    Ok(Table { data: data as usize, index: index as usize, count: offsets.len() - 1, checksum: writer.checksum() })
+}
+
+// A posting list's entry: the number of postings, then their encoding
+fn postings_entry(list: &PostingList) -> Vec<u8> {
+    let (len, bytes) = list.encoded();
+    let mut entry = len.to_le_bytes().to_vec();
+    entry.extend_from_slice(bytes);
+    entry
+}
+
+// Returns the footer's checksum, which stands for the whole segment
+fn write_footer<W: Write>(writer: &mut CountingWriter<W>, footer: &[Table; TABLES]) -> io::Result<u32> {
+    writer.checksum();
+    for table in footer {
+        for value in [table.data, table.index, table.count] {
+            writer.write(&(value as u64).to_le_bytes())?;
+        }
+        writer.write(&table.checksum.to_le_bytes())?;
+    }
+    let checksum = writer.checksum();
+    writer.write(&checksum.to_le_bytes())?;
+    writer.write(&SEGMENT_VERSION.to_le_bytes())?;
+    writer.write(MAGIC)?;
+    writer.inner.flush()?;
+    Ok(checksum)
+}
+
+// Returns the segment's checksum
+pub(crate) fn write<W: Write>(writer: W, index: &Index, docs: &Docs) -> io::Result<u32> {
+    let mut writer = CountingWriter::new(writer);
+    writer.write(MAGIC)?;
+
+    let hashes: Vec<&String> = index.doc_hashes().map(|(_, hash)| hash).collect();
+    let mut footer = [Table::default(); TABLES];
+    footer[DOC_HASHES] = write_table(&mut writer, hashes.iter())?;
+    footer[LANGUAGES] = write_table(&mut writer, hashes.iter().map(|hash| {
+        docs.get(*hash).and_then(|doc| doc.language.clone()).unwrap_or_default()
+    }))?;
+    // json can't fail for documents, all their map keys are strings
+    let documents = hashes.iter().map(|hash| serde_json::to_vec(&docs.get(*hash)).unwrap_or_default());
+    footer[DOCUMENTS] = write_table(&mut writer, documents)?;
+    footer[FIELDS] = write_table(&mut writer, index.fields().iter())?;
+    footer[TERMS] = write_table(&mut writer, index.terms().map(|(term, _)| term))?;
+    footer[POSTINGS] = write_table(&mut writer, index.terms().map(|(_, list)| postings_entry(list)))?;
+    write_footer(&mut writer, &footer)
+}
+
+// Writes the given documents of each input, by doc id, to one segment without
+// loading them: stored documents are copied as they are and the inputs' term
+// dictionaries are merged a term at a time. Postings go before the terms, so
+// only the terms that still have postings are kept, and those point into the
+// inputs' maps.
+pub(crate) fn write_merged<W: Write>(writer: W, inputs: &[(&Segment, Vec<DocId>)]) -> io::Result<u32> {
+    let mut writer = CountingWriter::new(writer);
+    writer.write(MAGIC)?;
+
+    // each input's doc ids in the merged segment, None for the dropped ones
+    let mut next = 0;
+    let mut ids = Vec::new();
+    for (segment, docs) in inputs {
+        let mut new_ids = vec![None; segment.doc_count()];
+        for doc in docs {
+            if let Some(id) = new_ids.get_mut(*doc as usize) {
+                *id = Some(next);
+                next += 1;
+            }
+        }
+        ids.push(new_ids);
+    }
+
+    let mut footer = [Table::default(); TABLES];
+    for table in [DOC_HASHES, LANGUAGES, DOCUMENTS] {
+        let entries = inputs
+            .iter()
+            .zip(&ids)
+            .flat_map(|((segment, _), ids)| (0..ids.len()).filter(|doc| ids[*doc].is_some()).map(|doc| segment.entry(table, doc)));
+        footer[table] = write_table(&mut writer, entries)?;
+    }
+
+    let mut fields: Vec<&str> = Vec::new();
+    let mut field_ids = Vec::new();
+    for (segment, _) in inputs {
+        let mut new_ids = Vec::new();
+        for field in 0..segment.tables[FIELDS].count {
+            let name = segment.text(FIELDS, field);
+            let id = fields.iter().position(|other| *other == name).unwrap_or_else(|| {
+                fields.push(name);
+                fields.len() - 1
+            });
+            new_ids.push(id as FieldId);
+        }
+        field_ids.push(new_ids);
+    }
+    footer[FIELDS] = write_table(&mut writer, fields.iter())?;
+
+    let mut cursors: Vec<_> = inputs.iter().map(|(segment, _)| segment.terms().peekable()).collect();
+    let mut terms = Vec::new();
+    let postings = std::iter::from_fn(|| loop {
+        let term = cursors.iter_mut().filter_map(|cursor| cursor.peek().map(|(term, _)| *term)).min()?;
+        let mut postings = Vec::new();
+        for (i, cursor) in cursors.iter_mut().enumerate() {
+            let Some((_, list)) = cursor.next_if(|(other, _)| *other == term) else { continue };
+            for posting in list {
+                let doc = ids[i].get(posting.doc as usize).copied().flatten();
+                let field = field_ids[i].get(posting.field as usize).copied();
+                if let (Some(doc), Some(field)) = (doc, field) {
+                    postings.push(Posting { doc, field, positions: posting.positions });
+                }
+            }
+        }
+        if postings.is_empty() {
+            continue;
+        }
+        // field ids were renumbered, so a document's postings may be out of order
+        postings.sort_by_key(|posting| (posting.doc, posting.field));
+        terms.push(term);
+        return Some(postings_entry(&PostingList::from_postings(&postings)));
+    });
+    footer[POSTINGS] = write_table(&mut writer, postings)?;
+    footer[TERMS] = write_table(&mut writer, terms.iter())?;
+    write_footer(&mut writer, &footer)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Table {
+    data: usize,
+    index: usize,
+    count: usize,
+    // crc32 of the data and offsets
+    checksum: u32,
+}
+
+impl Table {
+    fn end(&self) -> Option<usize> {
+        self.count.checked_add(1)?.checked_mul(8)?.checked_add(self.index)
+    }
+}
+
+pub(crate) struct Segment {
+    map: Mmap,
+    tables: [Table; TABLES],
+    // crc32 of the footer
+    checksum: u32,
+}
+
+impl Segment {
+    pub(crate) fn open(path: &Path) -> io::Result<Segment> {
+        let file = File::open(path)?;
+        // Safety: segments are never modified once written, updates and merges
+        // write new ones
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < MAGIC.len() + FOOTER_LEN || &map[..MAGIC.len()] != MAGIC || !map.ends_with(MAGIC) {
+            return Err(invalid("Not an index segment, or truncated"));
+        }
+        let footer = map.len() - FOOTER_LEN;
+        let read_u32 = |at: usize| u32::from_le_bytes(map[at..at + 4].try_into().unwrap());
+        let tables_end = footer + TABLES * TABLE_FOOTER_LEN;
+        let version = read_u32(tables_end + 4);
+        if version != SEGMENT_VERSION {
+            return Err(invalid(&format!("Segment version {} but this build reads {}", version, SEGMENT_VERSION)));
+        }
+        let checksum = read_u32(tables_end);
+        if crc32fast::hash(&map[footer..tables_end]) != checksum {
+            return Err(invalid("Segment footer is corrupt"));
+        }
+        let mut tables = [Table::default(); TABLES];
+        for (i, table) in tables.iter_mut().enumerate() {
+            let at = footer + i * TABLE_FOOTER_LEN;
+            let value = |n: usize| read_u64(&map, at + n * 8).unwrap() as usize;
+            *table = Table { data: value(0), index: value(1), count: value(2), checksum: read_u32(at + 24) };
+            if table.data > table.index || table.end().is_none_or(|end| end > footer) {
+                return Err(invalid("Segment table out of bounds"));
+            }
+        }
+        let docs = tables[DOC_HASHES].count;
+        if tables[LANGUAGES].count != docs || tables[DOCUMENTS].count != docs || tables[TERMS].count != tables[POSTINGS].count {
+            return Err(invalid("Segment tables don't line up"));
+        }
+        Ok(Segment { map, tables, checksum })
+    }
+
+    // Out of range or corrupt entries come back empty rather than panicking
+    fn entry(&self, table: usize, i: usize) -> &[u8] {
+        let table = self.tables[table];
+        if i >= table.count {
+            return &[];
+        }
+        let offset = |n: usize| read_u64(&self.map, table.index + n * 8).and_then(|offset| table.data.checked_add(offset as usize));
+        match (offset(i), offset(i + 1)) {
+            (Some(start), Some(end)) if start <= end && end <= table.index => &self.map[start..end],
+            _ => &[],
+        }
+    }
+
+    fn text(&self, table: usize, i: usize) -> &str {
+        std::str::from_utf8(self.entry(table, i)).unwrap_or("")
+    }
+
+    // crc32 of the footer, which has the tables' crc32s
+    pub(crate) fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    // Reads every table to check it against its crc32
+    pub(crate) fn verify(&self) -> io::Result<()> {
+        for (table, name) in self.tables.iter().zip(TABLE_NAMES) {
+            let bytes = table.end().and_then(|end| self.map.get(table.data..end)).unwrap_or(&[]);
+            if crc32fast::hash(bytes) != table.checksum {
+                return Err(invalid(&format!("Segment {} table is corrupt", name)));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn doc_count(&self) -> usize {
+        self.tables[DOC_HASHES].count
+    }
+
+    pub(crate) fn doc_hash(&self, doc: DocId) -> &str {
+        self.text(DOC_HASHES, doc as usize)
+    }
+
+    pub(crate) fn language(&self, doc: DocId) -> Option<&str> {
+        Some(self.text(LANGUAGES, doc as usize)).filter(|language| !language.is_empty())
+    }
+
+    // Stored documents are only parsed when a result is shown
+    pub(crate) fn document(&self, doc: DocId) -> Option<Document> {
+        serde_json::from_slice(self.entry(DOCUMENTS, doc as usize)).ok().flatten()
+    }
+
+    pub(crate) fn field_name(&self, field: FieldId) -> &str {
+        self.text(FIELDS, field as usize)
+    }
+
+    // number of distinct terms
+    pub(crate) fn len(&self) -> usize {
+        self.tables[TERMS].count
+    }
+
+    fn postings_at(&self, i: usize) -> Option<PostingIter<'_>> {
+        let entry = self.entry(POSTINGS, i);
+        let len = u32::from_le_bytes(entry.get(..4)?.try_into().ok()?);
+        Some(PostingIter::new(&entry[4..], len))
+    }
+
+    // Binary search of the term dictionary
+    pub(crate) fn postings(&self, term: &str) -> Option<PostingIter<'_>> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.entry(TERMS, middle).cmp(term.as_bytes()) {
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return self.postings_at(middle),
+            }
+        }
+        None
+    }
+
+    // Every term with its postings, in term order
+    pub(crate) fn terms(&self) -> impl Iterator<Item = (&str, PostingIter<'_>)> {
+        (0..self.len()).filter_map(|i| Some((self.text(TERMS, i), self.postings_at(i)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use crate::postings::{DocTerms, Posting};
+
+    fn sample() -> (Index, Docs) {
+        let mut index = Index::new();
+        let mut docs = Docs::new();
+        for (hash, words) in [("QmA", "gm frens"), ("QmB", "gm")] {
+            let mut terms = DocTerms::new();
+            for (i, word) in words.split(' ').enumerate() {
+                terms.entry(word.to_string()).or_default().insert("body".to_string(), vec![i as u32]);
+            }
+            index.add(hash.to_string(), terms);
+            let document = Document { fields: BTreeMap::new(), text: words.to_string(), language: Some("en".to_string()) };
+            docs.insert(hash.to_string(), document);
+        }
+        (index, docs)
+    }
+
+    fn write_sample(dir: &Path) -> std::path::PathBuf {
+        let (index, docs) = sample();
+        let path = dir.join("test.seg");
+        write(File::create(&path).unwrap(), &index, &docs).unwrap();
+        path
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let segment = Segment::open(&write_sample(dir.path())).unwrap();
+        segment.verify().unwrap();
+        assert_eq!(segment.doc_count(), 2);
+        assert_eq!(segment.doc_hash(1), "QmB");
+        assert_eq!(segment.language(0), Some("en"));
+        assert_eq!(segment.document(0).unwrap().text, "gm frens");
+        assert_eq!(segment.len(), 2);
+
+        let gm: Vec<Posting> = segment.postings("gm").unwrap().collect();
+        assert_eq!(gm.iter().map(|posting| posting.doc).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(segment.field_name(gm[0].field), "body");
+        assert_eq!(segment.postings("frens").unwrap().next().unwrap().positions, vec![1]);
+        assert!(segment.postings("ngmi").is_none());
+        assert_eq!(segment.terms().map(|(term, _)| term).collect::<Vec<_>>(), vec!["frens", "gm"]);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_sample(dir.path());
+        let bytes = fs::read(&path).unwrap();
+        for len in [0, 4, MAGIC.len() + FOOTER_LEN - 1, bytes.len() - 1, bytes.len() / 2] {
+            fs::write(&path, &bytes[..len]).unwrap();
+            assert!(Segment::open(&path).is_err(), "opened a segment cut to {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_footers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_sample(dir.path());
+        let bytes = fs::read(&path).unwrap();
+        let footer = bytes.len() - FOOTER_LEN;
+        let tables_end = footer + TABLES * TABLE_FOOTER_LEN;
+
+        // a table's offsets pointing past the footer
+        let mut corrupt = bytes.clone();
+        corrupt[footer + 8..footer + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&path, &corrupt).unwrap();
+        assert!(Segment::open(&path).is_err());
+
+        // the same with a footer checksum to match
+        let checksum = crc32fast::hash(&corrupt[footer..tables_end]);
+        corrupt[tables_end..tables_end + 4].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(&path, &corrupt).unwrap();
+        assert!(Segment::open(&path).is_err());
+
+        // another format version
+        let mut corrupt = bytes.clone();
+        corrupt[tables_end + 4] ^= 0xff;
+        fs::write(&path, &corrupt).unwrap();
+        assert!(Segment::open(&path).is_err());
+    }
+
+    #[test]
+    fn corrupt_entries_read_as_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_sample(dir.path());
+        let mut bytes = fs::read(&path).unwrap();
+        // every byte of the table data, but not the offsets or footer
+        let footer = bytes.len() - FOOTER_LEN;
+        let data_end = read_u64(&bytes, footer + POSTINGS * TABLE_FOOTER_LEN + 8).unwrap() as usize;
+        for byte in &mut bytes[MAGIC.len()..data_end] {
+            *byte = 0xff;
+        }
+        fs::write(&path, &bytes).unwrap();
+        // only the footer is checked on open, verify finds the rest
+        let segment = Segment::open(&path).unwrap();
+        assert!(segment.verify().is_err());
+        assert_eq!(segment.doc_hash(0), "");
+        assert!(segment.document(0).is_none());
+        assert!(segment.postings("gm").is_none());
+        let _ = segment.terms().flat_map(|(_, postings)| postings).count();
+    }
+
+    #[test]
+    fn merges_inputs_with_their_own_field_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = Vec::new();
+        for (name, fields) in [("first", ["title", "body"]), ("second", ["body", "title"])] {
+            let mut index = Index::new();
+            let mut terms = DocTerms::new();
+            for (i, field) in fields.iter().enumerate() {
+                terms.entry("gm".to_string()).or_default().insert(field.to_string(), vec![i as u32]);
+            }
+            index.add(format!("Qm{}", name), terms);
+            // keeps the field ids in the order above
+            let mut other = DocTerms::new();
+            other.entry("ser".to_string()).or_default().insert(fields[1].to_string(), vec![0]);
+            index.add(format!("Qm{}2", name), other);
+            let path = dir.path().join(format!("{}.seg", name));
+            write(File::create(&path).unwrap(), &index, &Docs::new()).unwrap();
+            paths.push(path);
+        }
+        let first = Segment::open(&paths[0]).unwrap();
+        let second = Segment::open(&paths[1]).unwrap();
+
+        let path = dir.path().join("merged.seg");
+        write_merged(File::create(&path).unwrap(), &[(&first, vec![0, 1]), (&second, vec![1])]).unwrap();
+        let merged = Segment::open(&path).unwrap();
+        assert_eq!(merged.doc_count(), 3);
+        assert_eq!(merged.doc_hash(2), "Qmsecond2");
+
+        let gm: Vec<(DocId, &str)> = merged.postings("gm").unwrap().map(|p| (p.doc, merged.field_name(p.field))).collect();
+        assert_eq!(gm.len(), 2);
+        assert!(gm.iter().all(|(doc, _)| *doc == 0));
+        let ser: Vec<(DocId, &str)> = merged.postings("ser").unwrap().map(|p| (p.doc, merged.field_name(p.field))).collect();
+        assert_eq!(ser, vec![(1, "body"), (2, "title")]);
+    }
+}
@@ -0,0 +1,193 @@
+// Content type detection from the first bytes of a body
+
+// How much of a body we want before deciding what it is
+pub(crate) const SNIFF_LEN: usize = 512;
+
+pub(crate) const ZIP: &str = "application/zip";
+pub(crate) const DOCX: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+pub(crate) const ODT: &str = "application/vnd.oasis.opendocument.text";
+pub(crate) const EPUB: &str = "application/epub+zip";
+
+// (offset, magic bytes, mime type)
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (8, b"WEBP", "image/webp"),
+    (0, b"II*\x00", "image/tiff"),
+    (0, b"MM\x00*", "image/tiff"),
+    (0, b"\x00\x00\x01\x00", "image/x-icon"),
+    (4, b"ftypavif", "image/avif"),
+    (4, b"ftypheic", "image/heic"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+    (8, b"AVI ", "video/x-msvideo"),
+    (8, b"WAVE", "audio/wav"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"\xff\xfb", "audio/mpeg"),
+    (0, b"\xff\xf3", "audio/mpeg"),
+    (0, b"PK\x03\x04", ZIP),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (0, b"Rar!\x1a\x07", "application/vnd.rar"),
+    (0, b"\x00asm", "application/wasm"),
+    (0, b"\x7fELF", "application/x-executable"),
+    (0, b"wOFF", "font/woff"),
+    (0, b"wOF2", "font/woff2"),
+];
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+// Documents that are zip archives underneath. odt and epub start with an
+// uncompressed "mimetype" entry; docx has a word/ directory, which usually
+// shows up in the first few local file headers.
+fn sniff_zip(bytes: &[u8]) -> &'static str {
+    if contains(bytes, b"mimetypeapplication/epub+zip") {
+        EPUB
+    } else if contains(bytes, b"mimetypeapplication/vnd.oasis.opendocument.text") {
+        ODT
+    } else if contains(bytes, b"word/") {
+        DOCX
+    } else {
+        ZIP
+    }
+}
+
+pub(crate) fn sniff_magic(bytes: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|(offset, magic, _)| bytes.get(*offset..offset + magic.len()) == Some(*magic))
+        .map(|(_, _, mime)| if *mime == ZIP { sniff_zip(bytes) } else { *mime })
+}
+
+// Binary formats we can't pull text out of. Only their first few kilobytes are
+// worth downloading, for whatever metadata sits in the header. Plain zip isn't
+// one of them: docx, odt and epub are zips too, and the entry that gives them
+// away isn't always within the bytes we sniff.
+pub(crate) fn is_binary(mime: &str) -> bool {
+    let mime = mime.split(';').next().unwrap_or("").trim();
+    ["image/", "video/", "audio/", "font/"].iter().any(|prefix| mime.starts_with(prefix))
+        || matches!(
+            mime,
+            "application/gzip"
+                | "application/x-7z-compressed"
+                | "application/vnd.rar"
+                | "application/wasm"
+                | "application/x-executable"
+        )
+}
+
+// Content types gateways send when they don't know better
+const GENERIC_TYPES: &[&str] = &[
+    "",
+    "application/octet-stream",
+    "binary/octet-stream",
+    "application/unknown",
+    "application/x-unknown",
+    "unknown/unknown",
+];
+
+// Start of a document that is almost certainly html, compared lowercased
+const HTML_PREFIXES: &[&str] = &[
+    "<!doctype html", "<html", "<head", "<body", "<script", "<title", "<meta",
+    "<link", "<style", "<div", "<iframe", "<table", "<h1", "<p>", "<p ", "<a ", "<br", "<!--",
+];
+
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("pdf", "application/pdf"),
+    ("docx", DOCX),
+    ("odt", ODT),
+    ("epub", EPUB),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+// The mime type without parameters, lowercased
+pub(crate) fn essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+fn from_extension(name: &str) -> Option<&'static str> {
+    let (_, extension) = name.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    EXTENSIONS
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, mime)| *mime)
+}
+
+fn looks_like_text(bytes: &[u8]) -> bool {
+    // Control characters other than whitespace don't show up in text
+    let binary = bytes
+        .iter()
+        .filter(|b| **b < 0x20 && !matches!(**b, b'\n' | b'\r' | b'\t' | 0x0c | 0x1b))
+        .count();
+    !bytes.is_empty() && binary == 0
+}
+
+fn sniff_text(body: &[u8]) -> Option<&'static str> {
+    let body = body.strip_prefix(b"\xef\xbb\xbf").unwrap_or(body);
+    let start = body.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(body.len());
+    let head = &body[start..body.len().min(start + SNIFF_LEN)];
+    let lower = String::from_utf8_lossy(head).to_ascii_lowercase();
+
+    if HTML_PREFIXES.iter().any(|prefix| lower.starts_with(prefix)) {
+        Some("text/html")
+    } else if lower.starts_with("<svg") || (lower.starts_with("<?xml") && lower.contains("<svg")) {
+        Some("image/svg+xml")
+    } else if lower.starts_with("<?xml") {
+        Some("application/xml")
+    } else if (lower.starts_with('{') || lower.starts_with('['))
+        && serde_json::from_slice::<serde::de::IgnoredAny>(body).is_ok() {
+        Some("application/json")
+    } else if looks_like_text(head) {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+// The content type to extract with. Magic bytes win, since they can't be
+// wrong; then a specific Content-Type header; then the file extension if we
+// know the name; and finally a look at the text itself. Falls back to the
+// header so unknown content is still reported by what the gateway said.
+// A plain zip signature is the exception: the header or extension can tell
+// us which zip based document it is when we haven't seen enough of the body.
+pub(crate) fn content_type(header: &str, name: Option<&str>, body: &[u8]) -> String {
+    let magic = sniff_magic(body);
+    if let Some(mime) = magic.filter(|mime| *mime != ZIP) {
+        return mime.to_string();
+    }
+    let header_mime = essence(header);
+    if !GENERIC_TYPES.contains(&header_mime.as_str()) {
+        return header_mime;
+    }
+    if let Some(mime) = name.and_then(from_extension) {
+        return mime.to_string();
+    }
+    if let Some(mime) = magic {
+        return mime.to_string();
+    }
+    sniff_text(body).map(String::from).unwrap_or(header_mime)
+}
@@ -0,0 +1,783 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use serde::{Serialize, Deserialize};
+use crate::disk;
+use crate::index::Docs;
+use crate::postings::{DocId, Index};
+use crate::segment::Segment;
+
+// The index is a directory of immutable segments, listed oldest first in the
+// manifest. An update writes the documents it indexed to a new segment and
+// marks the ones that are gone or replaced in their segment's deletion bitmap.
+// Merging rewrites segments without their deleted documents.
+//
+// Pages' alt texts are kept with the index too, since they can be about
+// documents a later update indexes.
+//
+// Files are never changed in place: segments, deletion bitmaps and alt texts are written
+// under new names, then the manifest is replaced to point at them, so an
+// update or merge either happened completely or not at all. Files the
+// manifest doesn't list are leftovers of one that didn't finish.
+const MANIFEST_FILE: &str = "manifest.json";
+// Bump when segments, deletion bitmaps or the manifest change incompatibly
+pub(crate) const SCHEMA_VERSION: u32 = 2;
+// Indexes from before the manifest listed their segments here
+const LEGACY_LIST_FILE: &str = "segments";
+// Segments are grouped into tiers by size, each tier MERGE_FACTOR times
+// bigger than the one below, and a tier's segments are merged once there are
+// MERGE_FACTOR of them. A document gets rewritten once per tier it climbs
+// rather than on every merge.
+const MERGE_FACTOR: usize = 4;
+
+fn tier(docs: usize) -> u32 {
+    docs.max(1).ilog(MERGE_FACTOR)
+}
+
+// Alt texts by the content hash of the page they're on: (referenced CID, alt text)
+pub(crate) type AltTexts = BTreeMap<String, Vec<(String, String)>>;
+
+fn segment_file(name: &str) -> String {
+    format!("{}.seg", name)
+}
+
+fn alt_texts_file(name: &str) -> String {
+    format!("{}.alt", name)
+}
+
+// Deletions only ever grow, so the count tells a bitmap's generations apart
+fn deletions_file(name: &str, deleted: usize) -> String {
+    format!("{}.{}.del", name, deleted)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Manifest {
+    pub(crate) schema_version: u32,
+    pub(crate) segments: Vec<SegmentRecord>,
+    // the last block whose contenthash changes the index is up to date with
+    pub(crate) last_block: Option<u64>,
+    // the analyzer the segments' terms came from, see Analyzer::fingerprint
+    pub(crate) analyzer: String,
+    // the alt texts file and its crc32, None if there aren't any
+    #[serde(default)]
+    pub(crate) alt_texts: Option<(String, u32)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct SegmentRecord {
+    name: String,
+    docs: usize,
+    // crc32 of the segment's footer, which has a crc32 of each of its tables
+    checksum: u32,
+    // the current deletion bitmap's file and crc32, None if nothing's deleted
+    deletions: Option<(String, u32)>,
+}
+
+impl SegmentRecord {
+    fn files(&self) -> impl Iterator<Item = String> + '_ {
+        std::iter::once(segment_file(&self.name)).chain(self.deletions.iter().map(|(file, _)| file.clone()))
+    }
+}
+
+// One bit per document of a segment, set if it's deleted
+#[derive(Debug, Clone)]
+pub(crate) struct Deletions {
+    bits: Vec<u8>,
+}
+
+impl Deletions {
+    pub(crate) fn new(docs: usize) -> Self {
+        Deletions { bits: vec![0; docs.div_ceil(8)] }
+    }
+
+    pub(crate) fn from_bytes(mut bits: Vec<u8>, docs: usize) -> Self {
+        bits.resize(docs.div_ceil(8), 0);
+        Deletions { bits }
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub(crate) fn contains(&self, doc: DocId) -> bool {
+        self.bits.get(doc as usize / 8).is_some_and(|byte| byte & (1 << (doc % 8)) != 0)
+    }
+
+    fn insert(&mut self, doc: DocId) {
+        if let Some(byte) = self.bits.get_mut(doc as usize / 8) {
+            *byte |= 1 << (doc % 8);
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.bits.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    record: SegmentRecord,
+    // shared with a merge that's reading it
+    segment: Arc<Segment>,
+    deletions: Deletions,
+}
+
+// Where an update's documents go. The segment is written while searches go
+// on and only added to the store once it's complete, like a merge's.
+pub(crate) struct NewSegment {
+    dir: PathBuf,
+    name: String,
+}
+
+// A segment that's been written but isn't in the manifest yet
+pub(crate) struct Written {
+    name: String,
+    segment: Segment,
+    checksum: u32,
+}
+
+impl NewSegment {
+    pub(crate) fn write(self, index: &Index, docs: &Docs) -> io::Result<Written> {
+        let path = self.dir.join(segment_file(&self.name));
+        let checksum = disk::save_segment(&path, index, docs)?;
+        // just written, so there's nothing to check it against
+        let segment = Segment::open(&path)?;
+        Ok(Written { name: self.name, segment, checksum })
+    }
+}
+
+// Segments being merged, with their deletions as of when the merge started
+struct Merge {
+    dir: PathBuf,
+    name: String,
+    inputs: Vec<(String, Arc<Segment>, Deletions)>,
+}
+
+// Files that aren't listed are never read, so failing to remove them is harmless
+fn remove_files(dir: &Path, files: impl IntoIterator<Item = String>) {
+    for file in files {
+        let _ = fs::remove_file(dir.join(file));
+    }
+}
+
+// Clears out what an update or merge that didn't finish left behind
+fn remove_unlisted(dir: &Path, listed: &HashSet<String>) -> io::Result<()> {
+    for file in fs::read_dir(dir)? {
+        let name = file?.file_name().to_string_lossy().into_owned();
+        let ours = [".seg", ".del", ".alt", ".tmp"].iter().any(|extension| name.ends_with(extension));
+        if ours && !listed.contains(&name) {
+            println!("Removing {} from the index, it isn't in the manifest", name);
+            remove_files(dir, [name]);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) struct Store {
+    dir: PathBuf,
+    segments: Vec<Entry>,
+    // content hash -> (segment position, doc id) of every live document
+    live: HashMap<String, (usize, DocId)>,
+    // for naming the next segment
+    next: u64,
+    last_block: Option<u64>,
+    analyzer: String,
+    // languages of the live documents, kept up to date by commit
+    languages: BTreeSet<String>,
+    alt_texts: AltTexts,
+    // the file alt_texts was loaded from or saved to, and whether it's changed since
+    alt_texts_file: Option<(String, u32)>,
+    alt_texts_changed: bool,
+}
+
+impl Store {
+    // Every file the manifest lists is checked against its checksum. For
+    // segments that's the footer's, verify checks the rest. Files it doesn't
+    // list are removed.
+    pub(crate) fn open(dir: &Path) -> io::Result<Store> {
+        fs::create_dir_all(dir)?;
+        let store = Store::load(dir)?;
+        let mut listed: HashSet<String> = store.segments.iter().flat_map(|entry| entry.record.files()).collect();
+        listed.extend(store.alt_texts_file.iter().map(|(file, _)| file.clone()));
+        remove_unlisted(dir, &listed)?;
+        Ok(store)
+    }
+
+    // Opens the index as open does, without creating the directory or
+    // removing leftover files, for checking it
+    pub(crate) fn open_read_only(dir: &Path) -> io::Result<Store> {
+        Store::load(dir)
+    }
+
+    fn load(dir: &Path) -> io::Result<Store> {
+        let manifest = match disk::load_manifest(&dir.join(MANIFEST_FILE))? {
+            Some(manifest) => manifest,
+            None if dir.join(LEGACY_LIST_FILE).exists() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{} was written by an older version without a manifest, delete it to rebuild the index",
+                    dir.display())));
+            }
+            None => Manifest {
+                schema_version: SCHEMA_VERSION,
+                segments: Vec::new(),
+                last_block: None,
+                analyzer: String::new(),
+                alt_texts: None,
+            },
+        };
+
+        let mut segments = Vec::new();
+        for record in manifest.segments {
+            let path = dir.join(segment_file(&record.name));
+            let segment = disk::load_segment(&path, record.checksum)?;
+            if segment.doc_count() != record.docs {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "{} has {} documents but the manifest has {}", path.display(), segment.doc_count(), record.docs)));
+            }
+            let deletions = match &record.deletions {
+                Some((file, checksum)) => disk::load_deletions(&dir.join(file), *checksum, record.docs)?,
+                None => Deletions::new(record.docs),
+            };
+            segments.push(Entry { record, segment: Arc::new(segment), deletions });
+        }
+        let alt_texts = match &manifest.alt_texts {
+            Some((file, checksum)) => disk::load_alt_texts(&dir.join(file), *checksum)?,
+            None => AltTexts::new(),
+        };
+
+        let names = segments.iter().map(|entry| &entry.record.name).chain(manifest.alt_texts.iter().map(|(file, _)| file));
+        let next = names.filter_map(|name| name.split('.').next()?.parse::<u64>().ok()).max().map_or(1, |n| n + 1);
+        let mut store = Store {
+            dir: dir.to_path_buf(),
+            segments,
+            live: HashMap::new(),
+            next,
+            last_block: manifest.last_block,
+            analyzer: manifest.analyzer,
+            languages: BTreeSet::new(),
+            alt_texts,
+            alt_texts_file: manifest.alt_texts,
+            alt_texts_changed: false,
+        };
+        store.find_live();
+        store.find_languages();
+        Ok(store)
+    }
+
+    fn find_live(&mut self) {
+        self.live.clear();
+        for (i, entry) in self.segments.iter().enumerate() {
+            for doc in 0..entry.segment.doc_count() as DocId {
+                if !entry.deletions.contains(doc) {
+                    self.live.insert(entry.segment.doc_hash(doc).to_string(), (i, doc));
+                }
+            }
+        }
+    }
+
+    // Merges don't change which documents are live, so only commits call this
+    fn find_languages(&mut self) {
+        self.languages = self
+            .segments()
+            .flat_map(|(segment, deletions)| {
+                (0..segment.doc_count() as DocId)
+                    .filter(|doc| !deletions.contains(*doc))
+                    .filter_map(|doc| segment.language(doc))
+            })
+            .map(String::from)
+            .collect();
+    }
+
+    fn new_name(&mut self) -> String {
+        let name = format!("{:08}", self.next);
+        self.next += 1;
+        name
+    }
+
+    // Replaces the manifest, then removes the files it stopped listing
+    fn commit(&mut self, mut obsolete: Vec<String>) -> io::Result<()> {
+        if self.alt_texts_changed {
+            let file = alt_texts_file(&self.new_name());
+            let checksum = disk::save_alt_texts(&self.dir.join(&file), &self.alt_texts)?;
+            obsolete.extend(self.alt_texts_file.replace((file, checksum)).map(|(old, _)| old));
+            self.alt_texts_changed = false;
+        }
+        self.save_manifest(&self.segments)?;
+        remove_files(&self.dir, obsolete);
+        self.find_languages();
+        Ok(())
+    }
+
+    fn save_manifest(&self, segments: &[Entry]) -> io::Result<()> {
+        let manifest = Manifest {
+            schema_version: SCHEMA_VERSION,
+            segments: segments.iter().map(|entry| entry.record.clone()).collect(),
+            last_block: self.last_block,
+            analyzer: self.analyzer.clone(),
+            alt_texts: self.alt_texts_file.clone(),
+        };
+        disk::save_manifest(&self.dir.join(MANIFEST_FILE), &manifest)
+    }
+
+    // Returns the position of the segment the document was in
+    fn mark_deleted(&mut self, hash: &str) -> Option<usize> {
+        let (i, doc) = self.live.remove(hash)?;
+        self.segments[i].deletions.insert(doc);
+        Some(i)
+    }
+
+    // Writes new bitmaps for the segments and returns the files they replace
+    fn write_deletions(&mut self, touched: &BTreeSet<usize>) -> io::Result<Vec<String>> {
+        let mut obsolete = Vec::new();
+        for i in touched {
+            let entry = &mut self.segments[*i];
+            let file = deletions_file(&entry.record.name, entry.deletions.count());
+            let checksum = disk::save_deletions(&self.dir.join(&file), &entry.deletions)?;
+            if let Some((old, _)) = entry.record.deletions.replace((file, checksum)) {
+                obsolete.push(old);
+            }
+        }
+        Ok(obsolete)
+    }
+
+    pub(crate) fn contains(&self, hash: &str) -> bool {
+        self.live.contains_key(hash)
+    }
+
+    // Content hashes of the live documents
+    pub(crate) fn hashes(&self) -> impl Iterator<Item = &String> {
+        self.live.keys()
+    }
+
+    pub(crate) fn doc_count(&self) -> usize {
+        self.live.len()
+    }
+
+    pub(crate) fn last_block(&self) -> Option<u64> {
+        self.last_block
+    }
+
+    // Records that the index is up to date with the chain as of this block
+    pub(crate) fn set_last_block(&mut self, block: Option<u64>) -> io::Result<()> {
+        self.last_block = block;
+        self.commit(Vec::new())
+    }
+
+    // Analyzer::fingerprint of the analyzer the index was built with, empty
+    // for a new index
+    pub(crate) fn analyzer(&self) -> &str {
+        &self.analyzer
+    }
+
+    // Saved with the next change
+    pub(crate) fn set_analyzer(&mut self, fingerprint: String) {
+        self.analyzer = fingerprint;
+    }
+
+    // Reads every segment to check its tables against their checksums
+    pub(crate) fn verify(&self) -> io::Result<()> {
+        for entry in &self.segments {
+            let path = self.dir.join(segment_file(&entry.record.name));
+            entry.segment.verify().map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    // The alt texts of every page indexed so far
+    pub(crate) fn alt_texts(&self) -> &AltTexts {
+        &self.alt_texts
+    }
+
+    // Replaces a page's alt texts. Saved with the next change.
+    pub(crate) fn set_alt_texts(&mut self, page: String, alt_texts: Vec<(String, String)>) {
+        let changed = if alt_texts.is_empty() {
+            self.alt_texts.remove(&page).is_some()
+        } else {
+            self.alt_texts.insert(page, alt_texts.clone()).is_none_or(|old| old != alt_texts)
+        };
+        self.alt_texts_changed |= changed;
+    }
+
+    // Languages of the live documents
+    pub(crate) fn languages(&self) -> &BTreeSet<String> {
+        &self.languages
+    }
+
+    // Searches go through every segment and skip its deleted documents
+    pub(crate) fn segments(&self) -> impl Iterator<Item = (&Segment, &Deletions)> {
+        self.segments.iter().map(|entry| (entry.segment.as_ref(), &entry.deletions))
+    }
+
+    // Returns how many of them were live documents. Their alt texts go too.
+    pub(crate) fn delete(&mut self, hashes: &[String]) -> io::Result<usize> {
+        let found: Vec<usize> = hashes.iter().filter_map(|hash| self.mark_deleted(hash)).collect();
+        for hash in hashes {
+            self.set_alt_texts(hash.clone(), Vec::new());
+        }
+        let obsolete = self.write_deletions(&found.iter().copied().collect())?;
+        self.commit(obsolete)?;
+        Ok(found.len())
+    }
+
+    // Names the segment for an update's documents, to be written without
+    // holding the lock
+    pub(crate) fn new_segment(&mut self) -> NewSegment {
+        NewSegment { dir: self.dir.clone(), name: self.new_name() }
+    }
+
+    // Adds a written segment. Its documents replace live documents with the
+    // same content hash.
+    pub(crate) fn add_segment(&mut self, written: Written) -> io::Result<()> {
+        let Written { name, segment, checksum } = written;
+        let touched = (0..segment.doc_count() as DocId).filter_map(|doc| self.mark_deleted(segment.doc_hash(doc))).collect();
+        let obsolete = self.write_deletions(&touched)?;
+        let record = SegmentRecord { name, docs: segment.doc_count(), checksum, deletions: None };
+        let deletions = Deletions::new(record.docs);
+        self.segments.push(Entry { record, segment: Arc::new(segment), deletions });
+        self.commit(obsolete)?;
+        self.find_live();
+        Ok(())
+    }
+
+    // Drops every segment, for rebuilding from scratch
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        let old = std::mem::take(&mut self.segments);
+        self.live.clear();
+        self.last_block = None;
+        self.alt_texts_changed |= !self.alt_texts.is_empty();
+        self.alt_texts.clear();
+        self.commit(old.iter().flat_map(|entry| entry.record.files()).collect())
+    }
+
+    // The segments of the lowest tier that has MERGE_FACTOR of them, sized by
+    // their live documents, otherwise the segments that are mostly deleted
+    // documents
+    fn plan_merge(&mut self) -> Option<Merge> {
+        let live = |entry: &Entry| entry.segment.doc_count() - entry.deletions.count();
+        let mut tiers: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+        for (i, entry) in self.segments.iter().enumerate() {
+            tiers.entry(tier(live(entry))).or_default().push(i);
+        }
+        let picked = match tiers.into_values().find(|segments| segments.len() >= MERGE_FACTOR) {
+            Some(segments) => segments,
+            None => (0..self.segments.len())
+                .filter(|i| self.segments[*i].deletions.count() * 2 > self.segments[*i].segment.doc_count())
+                .collect(),
+        };
+        if picked.is_empty() {
+            return None;
+        }
+        let inputs = picked
+            .into_iter()
+            .map(|i| {
+                let entry = &self.segments[i];
+                (entry.record.name.clone(), Arc::clone(&entry.segment), entry.deletions.clone())
+            })
+            .collect();
+        Some(Merge { dir: self.dir.clone(), name: self.new_name(), inputs })
+    }
+
+    // Swaps the merged segment in for its inputs. Documents deleted while the
+    // merge ran are still in the merged segment, so they're deleted there. The
+    // new segment list is only used once the manifest listing it is saved.
+    fn finish_merge(&mut self, merge: Merge, merged: Option<(Segment, u32)>) -> io::Result<()> {
+        let mut deleted_since = HashSet::new();
+        for (name, segment, deletions) in &merge.inputs {
+            let Some(entry) = self.segments.iter().find(|entry| &entry.record.name == name) else { continue };
+            for doc in 0..segment.doc_count() as DocId {
+                if entry.deletions.contains(doc) && !deletions.contains(doc) {
+                    deleted_since.insert(segment.doc_hash(doc).to_string());
+                }
+            }
+        }
+
+        let (merged_away, kept): (Vec<&Entry>, Vec<&Entry>) = self
+            .segments
+            .iter()
+            .partition(|entry| merge.inputs.iter().any(|(name, _, _)| name == &entry.record.name));
+        let obsolete: Vec<String> = merged_away.iter().flat_map(|entry| entry.record.files()).collect();
+        let mut segments: Vec<Entry> = kept.into_iter().cloned().collect();
+        if let Some((segment, checksum)) = merged {
+            let mut deletions = Deletions::new(segment.doc_count());
+            for doc in 0..segment.doc_count() as DocId {
+                if deleted_since.contains(segment.doc_hash(doc)) {
+                    deletions.insert(doc);
+                }
+            }
+            let mut record = SegmentRecord { name: merge.name, docs: segment.doc_count(), checksum, deletions: None };
+            if deletions.count() > 0 {
+                let file = deletions_file(&record.name, deletions.count());
+                let checksum = disk::save_deletions(&self.dir.join(&file), &deletions)?;
+                record.deletions = Some((file, checksum));
+            }
+            segments.push(Entry { record, segment: Arc::new(segment), deletions });
+        }
+        self.save_manifest(&segments)?;
+        self.segments = segments;
+        self.find_live();
+        remove_files(&self.dir, obsolete);
+        Ok(())
+    }
+}
+
+// The merged segment and its checksum, None if none of the inputs'
+// documents are live
+fn write_merged(merge: &Merge) -> io::Result<Option<(Segment, u32)>> {
+    let inputs: Vec<(&Segment, Vec<DocId>)> = merge
+        .inputs
+        .iter()
+        .map(|(_, segment, deletions)| {
+            let live = (0..segment.doc_count() as DocId).filter(|doc| !deletions.contains(*doc)).collect();
+            (segment.as_ref(), live)
+        })
+        .collect();
+    if inputs.iter().all(|(_, live)| live.is_empty()) {
+        return Ok(None);
+    }
+    let path = merge.dir.join(segment_file(&merge.name));
+    let checksum = disk::save_merged(&path, &inputs)?;
+    Ok(Some((Segment::open(&path)?, checksum)))
+}
+
+// Merges on a thread of its own until there's nothing worth merging. The
+// merged segment is written without holding the lock, searches only wait
+// while it's swapped in.
+pub(crate) fn merge_in_background(store: Arc<RwLock<Store>>) {
+    thread::spawn(move || loop {
+        let Some(merge) = store.write().unwrap().plan_merge() else { return };
+        println!("Merging {} segments into {}", merge.inputs.len(), merge.name);
+        let merged = match write_merged(&merge) {
+            Ok(merged) => merged,
+            Err(e) => {
+                println!("Could not merge segments: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = store.write().unwrap().finish_merge(merge, merged) {
+            println!("Could not save merged segment: {}", e);
+            return;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::index::Document;
+    use crate::postings::DocTerms;
+
+    // One document per hash, each with the words in its body
+    fn build(docs: &[(&str, &str)]) -> (Index, Docs) {
+        let mut index = Index::new();
+        let mut stored = Docs::new();
+        for (hash, words) in docs {
+            let mut terms = DocTerms::new();
+            for (i, word) in words.split(' ').enumerate() {
+                terms.entry(word.to_string()).or_default().entry("body".to_string()).or_default().push(i as u32);
+            }
+            index.add(hash.to_string(), terms);
+            let document = Document { fields: BTreeMap::new(), text: words.to_string(), language: None };
+            stored.insert(hash.to_string(), document);
+        }
+        (index, stored)
+    }
+
+    fn add(store: &mut Store, index: &Index, docs: &Docs) {
+        let written = store.new_segment().write(index, docs).unwrap();
+        store.add_segment(written).unwrap();
+    }
+
+    fn matches(store: &Store, term: &str) -> Vec<String> {
+        let mut hashes: Vec<String> = store
+            .segments()
+            .flat_map(|(segment, deletions)| {
+                segment
+                    .postings(term)
+                    .into_iter()
+                    .flatten()
+                    .filter(|posting| !deletions.contains(posting.doc))
+                    .map(|posting| segment.doc_hash(posting.doc).to_string())
+            })
+            .collect();
+        hashes.sort();
+        hashes
+    }
+
+    fn merge_all(store: &mut Store) {
+        while let Some(merge) = store.plan_merge() {
+            let merged = write_merged(&merge).unwrap();
+            store.finish_merge(merge, merged).unwrap();
+        }
+    }
+
+    #[test]
+    fn deletions_set_bits() {
+        let mut deletions = Deletions::new(10);
+        assert_eq!(deletions.bytes().len(), 2);
+        deletions.insert(0);
+        deletions.insert(9);
+        deletions.insert(9);
+        // past the end, ignored
+        deletions.insert(16);
+        assert!(deletions.contains(0) && deletions.contains(9));
+        assert!(!deletions.contains(1) && !deletions.contains(8) && !deletions.contains(16));
+        assert_eq!(deletions.count(), 2);
+
+        let read = Deletions::from_bytes(deletions.bytes().to_vec(), 10);
+        assert!(read.contains(9));
+        assert_eq!(read.count(), 2);
+    }
+
+    #[test]
+    fn merging_drops_deleted_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+        let (index, docs) = build(&[("a", "gm frens"), ("b", "gm ser"), ("c", "wagmi")]);
+        add(&mut store, &index, &docs);
+        let (index, docs) = build(&[("d", "ser gm")]);
+        add(&mut store, &index, &docs);
+        store.delete(&["a".to_string(), "c".to_string()]).unwrap();
+
+        // the first segment is mostly deleted documents, so it's rewritten
+        // on its own and goes after the second
+        merge_all(&mut store);
+        assert_eq!(store.segment_count(), 2);
+        let (segment, deletions) = store.segments().last().unwrap();
+        assert_eq!(segment.doc_count(), 1);
+        assert_eq!(segment.doc_hash(0), "b");
+        assert!(!deletions.contains(0));
+        assert_eq!(segment.document(0).unwrap().text, "gm ser");
+        // terms only deleted documents had are gone
+        assert!(segment.postings("frens").is_none() && segment.postings("wagmi").is_none());
+        assert_eq!(matches(&store, "gm"), vec!["b", "d"]);
+        assert_eq!(matches(&store, "ser"), vec!["b", "d"]);
+
+        let reopened = Store::open(dir.path()).unwrap();
+        assert_eq!(reopened.doc_count(), 2);
+        assert_eq!(matches(&reopened, "gm"), vec!["b", "d"]);
+    }
+
+    #[test]
+    fn merges_segments_of_similar_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+        let big: Vec<(String, &str)> = (0..40).map(|i| (format!("big{}", i), "gm")).collect();
+        let big: Vec<(&str, &str)> = big.iter().map(|(hash, words)| (hash.as_str(), *words)).collect();
+        let (index, docs) = build(&big);
+        add(&mut store, &index, &docs);
+        for hash in ["a", "b", "c"] {
+            let (index, docs) = build(&[(hash, "gm")]);
+            add(&mut store, &index, &docs);
+        }
+        assert!(store.plan_merge().is_none());
+
+        let (index, docs) = build(&[("d", "gm")]);
+        add(&mut store, &index, &docs);
+        let merge = store.plan_merge().unwrap();
+        let mut names: Vec<&str> = merge.inputs.iter().map(|(name, _, _)| name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["00000002", "00000003", "00000004", "00000005"]);
+
+        let merged = write_merged(&merge).unwrap();
+        store.finish_merge(merge, merged).unwrap();
+        assert_eq!(store.segment_count(), 2);
+        assert_eq!(store.doc_count(), 44);
+        assert!(store.plan_merge().is_none());
+    }
+
+    #[test]
+    fn languages_follow_the_live_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+        let (index, mut docs) = build(&[("a", "gm"), ("b", "moin")]);
+        docs.get_mut("a").unwrap().language = Some("en".to_string());
+        docs.get_mut("b").unwrap().language = Some("de".to_string());
+        add(&mut store, &index, &docs);
+        assert_eq!(store.languages().iter().collect::<Vec<_>>(), vec!["de", "en"]);
+
+        store.delete(&["b".to_string()]).unwrap();
+        assert_eq!(store.languages().iter().collect::<Vec<_>>(), vec!["en"]);
+        assert_eq!(Store::open(dir.path()).unwrap().languages().iter().collect::<Vec<_>>(), vec!["en"]);
+    }
+
+    #[test]
+    fn alt_texts_are_saved_with_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+        let (index, docs) = build(&[("a", "gm"), ("b", "ser")]);
+        store.set_alt_texts("a".to_string(), vec![("QmImage".to_string(), "a cat".to_string())]);
+        store.set_alt_texts("b".to_string(), vec![("QmOther".to_string(), "a dog".to_string())]);
+        add(&mut store, &index, &docs);
+
+        let mut store = Store::open(dir.path()).unwrap();
+        assert_eq!(store.alt_texts().len(), 2);
+        assert_eq!(store.alt_texts()["a"], vec![("QmImage".to_string(), "a cat".to_string())]);
+
+        // a deleted page's alt texts go with it, and the old file is replaced
+        store.delete(&["b".to_string()]).unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        assert_eq!(store.alt_texts().keys().collect::<Vec<_>>(), vec!["a"]);
+        let files = fs::read_dir(dir.path()).unwrap().filter(|file| file.as_ref().unwrap().file_name().to_string_lossy().ends_with(".alt"));
+        assert_eq!(files.count(), 1);
+        // named from the same counter as segments
+        assert_eq!(store.alt_texts_file.as_ref().unwrap().0, "00000003.alt");
+    }
+
+    #[test]
+    fn read_only_opens_leave_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        assert_eq!(Store::open_read_only(&missing).unwrap().segment_count(), 0);
+        assert!(!missing.exists());
+
+        let mut store = Store::open(dir.path()).unwrap();
+        let (index, docs) = build(&[("a", "gm")]);
+        add(&mut store, &index, &docs);
+        drop(store);
+        let leftover = dir.path().join(segment_file("00000009"));
+        fs::write(&leftover, b"half a merge").unwrap();
+        let store = Store::open_read_only(dir.path()).unwrap();
+        store.verify().unwrap();
+        assert!(leftover.exists());
+        Store::open(dir.path()).unwrap();
+        assert!(!leftover.exists());
+    }
+
+    #[test]
+    fn open_rejects_segments_that_dont_match_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = Store::open(dir.path()).unwrap();
+        let (index, docs) = build(&[("a", "gm")]);
+        add(&mut store, &index, &docs);
+        let (index, docs) = build(&[("b", "ser")]);
+        add(&mut store, &index, &docs);
+        drop(store);
+
+        // another segment's file in place of the first
+        let first = dir.path().join(segment_file("00000001"));
+        let second = dir.path().join(segment_file("00000002"));
+        let original = fs::read(&first).unwrap();
+        fs::copy(&second, &first).unwrap();
+        let e = Store::open(dir.path()).err().unwrap();
+        assert!(e.to_string().contains("checksum"), "{}", e);
+
+        // table data corrupted under an intact footer only shows up in verify
+        let mut corrupt = original.clone();
+        let at = corrupt.windows(2).position(|window| window == b"gm").unwrap();
+        corrupt[at] = b'x';
+        fs::write(&first, &corrupt).unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        assert!(store.verify().is_err());
+
+        fs::write(&first, &original).unwrap();
+        Store::open(dir.path()).unwrap().verify().unwrap();
+    }
+}